@@ -20,6 +20,10 @@ fi
 mkdir -p $ISO_ROOT
 cp conf/limine.conf $LIMINE_BOOTLOADER_REPO/limine{-bios.sys,-bios-cd.bin,-uefi-cd.bin} $ISO_ROOT
 cp $KERNEL $ISO_ROOT/nexos
+# Test binaries are built into deps/, they keep the same kernel virtual memory layout on every boot
+if [[ $KERNEL == */deps/* ]]; then
+    echo "    cmdline: nokaslr" >> $ISO_ROOT/limine.conf
+fi
 mkdir -p $ISO_ROOT/EFI/BOOT
 cp -v $LIMINE_BOOTLOADER_REPO/BOOTX64.EFI $ISO_ROOT/EFI/BOOT/
 cp -v $LIMINE_BOOTLOADER_REPO/BOOTIA32.EFI $ISO_ROOT/EFI/BOOT/
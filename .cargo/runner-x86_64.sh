@@ -9,7 +9,7 @@ qemu-system-x86_64 \
     -bios ${OVMF_PATH:-"/usr/share/OVMF/OVMF_CODE.fd"} \
     $QEMU_FLAGS \
     -enable-kvm \
-    -machine q35 -cpu qemu64 -M smm=off \
+    -machine q35 -cpu qemu64,+smep,+smap -M smm=off \
     -D target/x86_64-log.txt -d int,guest_errors -no-reboot \
     -serial stdio \
     -m $MEMORY -smp 4 \
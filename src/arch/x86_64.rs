@@ -1,9 +1,21 @@
+pub mod acpi;
+pub mod address_space;
 pub mod apic;
+pub mod exceptions;
+pub mod gdt;
 pub mod idt;
+pub mod ioapic;
+pub mod kernel_image;
 pub mod paging;
 pub mod pic;
+pub mod pit;
 pub mod ports;
+pub mod random;
 pub mod serial;
+pub mod smp;
+pub mod timer;
+pub mod tlb;
+pub mod user_access;
 use crate::kernel::logger::Logger;
 pub struct LoggerX86Impl(());
 impl LoggerX86Impl {
@@ -0,0 +1,128 @@
+use core::ptr::read_unaligned;
+
+use alloc::vec::Vec;
+
+use crate::{
+    kernel::memory_map::physical_to_virtual,
+    limine::{HHDM, RSDP},
+};
+
+/// Size of the header every system description table starts with
+const HEADER_SIZE: usize = 36;
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// Where the entries of the MADT start, after the local APIC address and the flags
+const MADT_ENTRIES_OFFSET: usize = HEADER_SIZE + 8;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+
+/// A system description table, read through the higher half direct map
+#[derive(Debug, Clone, Copy)]
+struct Table {
+    address: usize,
+    length: usize,
+}
+impl Table {
+    /// # Safety
+    /// `physical_address` must point to a table
+    unsafe fn at(physical_address: usize) -> Self {
+        let address = physical_to_virtual(physical_address);
+        Self {
+            address,
+            length: read_unaligned((address + 4) as *const u32) as usize,
+        }
+    }
+    fn signature(&self) -> [u8; 4] {
+        self.read(0)
+    }
+    /// Every byte of a valid table adds up to 0
+    fn checksum_valid(&self) -> bool {
+        (0..self.length).fold(0u8, |sum, offset| sum.wrapping_add(self.read(offset))) == 0
+    }
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(
+            offset + size_of::<T>() <= self.length,
+            "Read at 0x{offset:X} is outside of the table"
+        );
+        unsafe { read_unaligned((self.address + offset) as *const T) }
+    }
+}
+
+/// Finds the table with `signature` through the RSDT or XSDT the bootloader found
+fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    let mut rsdp = RSDP.get_response()?.address() as usize;
+    // Older protocol revisions give the address in the direct map, newer ones the physical one
+    let hhdm_offset = HHDM.get_response()?.offset() as usize;
+    if rsdp >= hhdm_offset {
+        rsdp -= hhdm_offset;
+    }
+    let rsdp = physical_to_virtual(rsdp);
+    let revision = unsafe { *((rsdp + 15) as *const u8) };
+    // ACPI 2.0 and later have the XSDT with 64 bit pointers
+    let (root, pointer_size) = unsafe {
+        if revision >= 2 {
+            (read_unaligned((rsdp + 24) as *const u64) as usize, 8)
+        } else {
+            (read_unaligned((rsdp + 16) as *const u32) as usize, 4)
+        }
+    };
+    let root = unsafe { Table::at(root) };
+    (HEADER_SIZE..root.length)
+        .step_by(pointer_size)
+        .map(|offset| match pointer_size {
+            8 => root.read::<u64>(offset) as usize,
+            _ => root.read::<u32>(offset) as usize,
+        })
+        .map(|address| unsafe { Table::at(address) })
+        .find(|table| &table.signature() == signature && table.checksum_valid())
+}
+
+/// Entries of the MADT the kernel cares about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    IoApic {
+        id: u8,
+        address: u32,
+        /// First global system interrupt the I/O APIC handles
+        gsi_base: u32,
+    },
+    /// An ISA IRQ connected to another global system interrupt than its number
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        /// MPS INTI flags with the polarity and trigger mode
+        flags: u16,
+    },
+}
+
+/// I/O APICs and interrupt source overrides from the MADT, empty if the firmware has none
+pub fn madt_entries() -> Vec<MadtEntry> {
+    let Some(madt) = find_table(MADT_SIGNATURE) else {
+        return Vec::new();
+    };
+    let mut entries = Vec::new();
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= madt.length {
+        let kind: u8 = madt.read(offset);
+        let length: u8 = madt.read(offset + 1);
+        if length < 2 {
+            break;
+        }
+        match kind {
+            MADT_IO_APIC => entries.push(MadtEntry::IoApic {
+                id: madt.read(offset + 2),
+                address: madt.read(offset + 4),
+                gsi_base: madt.read(offset + 8),
+            }),
+            MADT_INTERRUPT_SOURCE_OVERRIDE => entries.push(MadtEntry::InterruptSourceOverride {
+                bus: madt.read(offset + 2),
+                source: madt.read(offset + 3),
+                gsi: madt.read(offset + 4),
+                flags: madt.read(offset + 8),
+            }),
+            _ => {}
+        }
+        offset += length as usize;
+    }
+    entries
+}
@@ -0,0 +1,379 @@
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::Ordering,
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{OffsetPageTable, PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use super::{paging::X86MemoryMap, smp::MAX_CORES, tlb, user_access::USER_SPACE_END};
+use crate::{
+    bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+    kernel::{
+        memory_area::{FaultAccess, MemoryAreas, KERNEL_MEMORY_AREAS},
+        memory_map::{physical_to_virtual, MemoryMap},
+        memory_stats::PAGE_TABLE_PAGES,
+        KERNEL_MEMORY_MAP,
+    },
+    limine::HHDM,
+    multicore::{current_core_id, lock::CoreMutex},
+};
+
+/// Level 4 entries from this one onwards map the higher half, which is shared by every address space
+const FIRST_KERNEL_ENTRY: usize = 256;
+
+/// An address space loaded with [`AddressSpace::activate`] and the level 4 frame it was loaded with
+#[derive(Clone, Copy)]
+struct ActiveAddressSpace {
+    address_space: NonNull<AddressSpace>,
+    level_4_frame: PhysFrame,
+}
+// The pointer is only dereferenced by the core it was activated on, while its slot is locked
+unsafe impl Send for ActiveAddressSpace {}
+
+/// Address space last loaded with [`AddressSpace::activate`] on each core, cleared on every core when it is dropped
+///
+/// A slot is only locked with interrupts disabled, or by the page fault handler of its own core,
+/// which keeps it while the fault is resolved and other cores flush their TLBs.
+static ACTIVE_ADDRESS_SPACES: [CoreMutex<Option<ActiveAddressSpace>>; MAX_CORES] =
+    [const { CoreMutex::new(None) }; MAX_CORES];
+
+/// Gives access to the page table stored in `frame` through the higher half direct map
+pub(super) unsafe fn page_table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    &mut *(physical_to_virtual(frame.start_address().as_u64() as usize) as *mut PageTable)
+}
+
+pub(super) fn allocate_page_table() -> Option<PhysFrame> {
+    let frame = GLOBAL_PAGE_ALLOCATOR.lock().request_and_clear_page()?;
+    PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+    Some(PhysFrame::containing_address(PhysAddr::new(
+        frame.get() as u64
+    )))
+}
+
+unsafe fn free_page_table(frame: PhysFrame) {
+    GLOBAL_PAGE_ALLOCATOR
+        .lock()
+        .free_pages(frame.start_address().as_u64() as usize, PAGE_SIZE);
+    PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Frees every table below `table`, which is at `level`, without touching the frames they map
+unsafe fn free_child_tables(table: &PageTable, level: usize) {
+    if level == 1 {
+        return;
+    }
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+        let Ok(frame) = entry.frame() else {
+            continue;
+        };
+        free_child_tables(page_table(frame), level - 1);
+        free_page_table(frame);
+    }
+}
+
+/// An independent set of page tables, where the lower half is private and the higher half is the kernel's
+///
+/// Kernel mappings are shared through the level 3 tables, so they show up in every address space.
+pub struct AddressSpace {
+    memory_map: X86MemoryMap<OffsetPageTable<'static>>,
+    /// Demand paged areas of the lower half
+    areas: MemoryAreas,
+    /// Held while a page fault is resolved or pages are shared, so cores faulting on the same page don't race
+    faults: CoreMutex<()>,
+}
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the lower half
+    pub fn new() -> Option<Self> {
+        let mut kernel_memory_map = KERNEL_MEMORY_MAP.lock();
+        let kernel_table = kernel_memory_map.level_4_table();
+        // Every higher half entry has to exist before it is copied, or kernel mappings added
+        // later would only be seen by the kernel address space
+        for entry in kernel_table.iter_mut().skip(FIRST_KERNEL_ENTRY) {
+            if entry.is_unused() {
+                entry.set_frame(
+                    allocate_page_table()?,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                );
+            }
+        }
+        let frame = allocate_page_table()?;
+        let table = unsafe { page_table(frame) };
+        for (entry, kernel_entry) in table
+            .iter_mut()
+            .zip(kernel_table.iter())
+            .skip(FIRST_KERNEL_ENTRY)
+        {
+            *entry = kernel_entry.clone();
+        }
+        let offset = VirtAddr::new(HHDM.get_response().unwrap().offset());
+        Some(Self {
+            memory_map: unsafe {
+                X86MemoryMap::with_pcid(
+                    OffsetPageTable::new(table, offset),
+                    frame,
+                    tlb::allocate_pcid(),
+                )
+            },
+            areas: MemoryAreas::new(),
+            faults: CoreMutex::new(()),
+        })
+    }
+    /// Whether this address space is loaded on the current core
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.memory_map.level_4_frame()
+    }
+    /// Loads this address space on the current core, page faults in the lower half are then resolved with its areas
+    ///
+    /// # Safety
+    /// Same as [`MemoryMap::load_memory_map`], and the address space must not move while it is active
+    pub unsafe fn activate(&mut self) {
+        let active = ActiveAddressSpace {
+            address_space: NonNull::from(&mut *self),
+            level_4_frame: self.memory_map.level_4_frame(),
+        };
+        interrupts::without_interrupts(|| {
+            *ACTIVE_ADDRESS_SPACES[current_core_id()].lock() = Some(active);
+        });
+        self.memory_map.load_memory_map();
+    }
+    /// Creates a copy of this address space like `fork` does, the pages of its areas are shared copy-on-write
+    ///
+    /// Pages mapped outside of the areas are not copied.
+    pub fn try_clone(&mut self) -> Option<Self> {
+        let mut clone = Self::new()?;
+        let _faults = self.faults.lock();
+        let shared = unsafe {
+            self.areas.share(
+                &mut self.memory_map,
+                &mut clone.areas,
+                &mut clone.memory_map,
+            )
+        };
+        // Pages shared so far stay copy-on-write here, their first write finds they aren't shared anymore
+        shared.then_some(clone)
+    }
+    pub fn areas(&self) -> &MemoryAreas {
+        &self.areas
+    }
+    pub fn areas_mut(&mut self) -> &mut MemoryAreas {
+        &mut self.areas
+    }
+}
+/// Resolves a page fault at `address` with the demand paged areas of the kernel or of the active address space,
+/// returns false if no area covers it
+pub fn handle_page_fault(address: usize, access: FaultAccess) -> bool {
+    if address >= USER_SPACE_END {
+        // Waiting for a lock this core already holds would hang, the fault is reported instead,
+        // other cores holding them are waited for
+        if KERNEL_MEMORY_AREAS.is_held_by_current_core()
+            || KERNEL_MEMORY_MAP.is_held_by_current_core()
+        {
+            return false;
+        }
+        let areas = KERNEL_MEMORY_AREAS.lock();
+        return unsafe {
+            areas.handle_page_fault(address, access, KERNEL_MEMORY_MAP.lock().deref_mut())
+        };
+    }
+    // The address space can't be dropped while its slot is held
+    let active = ACTIVE_ADDRESS_SPACES[current_core_id()].lock();
+    let Some(ActiveAddressSpace {
+        address_space,
+        level_4_frame,
+    }) = *active
+    else {
+        return false;
+    };
+    // Tables loaded without activating their address space must not be resolved with the last active one
+    if Cr3::read().0 != level_4_frame {
+        return false;
+    }
+    let address_space = address_space.as_ptr();
+    unsafe {
+        let _faults = (*address_space).faults.lock();
+        (*address_space)
+            .areas
+            .handle_page_fault(address, access, &mut (*address_space).memory_map)
+    }
+}
+
+impl Deref for AddressSpace {
+    type Target = X86MemoryMap<OffsetPageTable<'static>>;
+    fn deref(&self) -> &Self::Target {
+        &self.memory_map
+    }
+}
+impl DerefMut for AddressSpace {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.memory_map
+    }
+}
+impl Drop for AddressSpace {
+    /// Frees the page tables and demand paged frames of the lower half, other frames mapped in it are left to their owners
+    fn drop(&mut self) {
+        assert!(
+            !self.is_active(),
+            "Can't drop the address space loaded on this core"
+        );
+        let this = NonNull::from(&mut *self);
+        // A core resolving a fault keeps its slot until every other core flushed its TLB,
+        // this one does its flush while waiting for the slot
+        interrupts::without_interrupts(|| {
+            for active in &ACTIVE_ADDRESS_SPACES {
+                let mut active = active.lock();
+                if active.is_some_and(|active| active.address_space == this) {
+                    *active = None;
+                }
+            }
+        });
+        unsafe { self.areas.clear(&mut self.memory_map) };
+        let table = self.memory_map.level_4_table();
+        unsafe {
+            for entry in table.iter_mut().take(FIRST_KERNEL_ENTRY) {
+                if let Ok(frame) = entry.frame() {
+                    free_child_tables(page_table(frame), 3);
+                    free_page_table(frame);
+                }
+                entry.set_unused();
+            }
+            free_page_table(self.memory_map.level_4_frame());
+            tlb::release_pcid(self.memory_map.pcid());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+    use crate::{
+        arch::x86_64::user_access::{copy_from_user, copy_to_user},
+        kernel::memory_map::MemoryFlags,
+    };
+
+    #[test(name = "Create an address space, switch to it and back")]
+    fn test_address_space() {
+        const USER_ADDRESS: usize = 0x40_0000;
+        // The first address space also fills in the kernel higher half entries
+        drop(AddressSpace::new().unwrap());
+        let kernel_value = Box::new(0x1234u64);
+        let frame = GLOBAL_PAGE_ALLOCATOR.lock().request_page().unwrap().get();
+        let page_tables_before = PAGE_TABLE_PAGES.load(Ordering::Relaxed);
+
+        let mut address_space = AddressSpace::new().unwrap();
+        unsafe {
+            assert!(address_space.map_memory(USER_ADDRESS, frame, MemoryFlags::WRITABLE));
+            assert_eq!(
+                address_space
+                    .translate(USER_ADDRESS)
+                    .map(|(address, _)| address),
+                Some(frame)
+            );
+            assert_eq!(KERNEL_MEMORY_MAP.lock().translate(USER_ADDRESS), None);
+            // The higher half is the same in both address spaces
+            let kernel_value_address = &*kernel_value as *const u64 as usize;
+            assert_eq!(
+                address_space.translate(kernel_value_address),
+                KERNEL_MEMORY_MAP.lock().translate(kernel_value_address)
+            );
+
+            address_space.load_memory_map();
+            assert!(address_space.is_active());
+            (USER_ADDRESS as *mut u64).write_volatile(*kernel_value);
+            KERNEL_MEMORY_MAP.lock().load_memory_map();
+            assert!(!address_space.is_active());
+        }
+        assert_eq!(
+            unsafe { (physical_to_virtual(frame) as *const u64).read_volatile() },
+            0x1234
+        );
+        drop(address_space);
+        assert_eq!(PAGE_TABLE_PAGES.load(Ordering::Relaxed), page_tables_before);
+        GLOBAL_PAGE_ALLOCATOR.lock().free_pages(frame, PAGE_SIZE);
+    }
+
+    #[test(name = "Lower half pages are demand paged from the areas of the active address space")]
+    fn test_address_space_demand_paging() {
+        const USER_ADDRESS: usize = 0x80_0000;
+        let mut address_space = AddressSpace::new().unwrap();
+        assert!(address_space.areas_mut().insert(
+            USER_ADDRESS..USER_ADDRESS + 4 * PAGE_SIZE,
+            MemoryFlags::USER_DATA,
+            None
+        ));
+        let mut copied = [0u8; 6];
+        let free_pages_before;
+        unsafe {
+            address_space.activate();
+            free_pages_before = GLOBAL_PAGE_ALLOCATOR.lock().free_page_count();
+            assert!(copy_to_user(USER_ADDRESS + PAGE_SIZE, b"demand"));
+            assert!(copy_from_user(&mut copied, USER_ADDRESS + PAGE_SIZE));
+            KERNEL_MEMORY_MAP.lock().load_memory_map();
+        }
+        assert_eq!(&copied, b"demand");
+        assert!(address_space.translate(USER_ADDRESS).is_none());
+        assert!(address_space.translate(USER_ADDRESS + PAGE_SIZE).is_some());
+        // The frame and the page tables needed for it are freed with the address space
+        drop(address_space);
+        assert!(GLOBAL_PAGE_ALLOCATOR.lock().free_page_count() > free_pages_before);
+    }
+
+    #[test(name = "Cloned address spaces share pages until one of them writes")]
+    fn test_copy_on_write_clone() {
+        const USER_ADDRESS: usize = 0xA0_0000;
+        let mut parent = AddressSpace::new().unwrap();
+        assert!(parent.areas_mut().insert(
+            USER_ADDRESS..USER_ADDRESS + 2 * PAGE_SIZE,
+            MemoryFlags::USER_DATA,
+            None
+        ));
+        unsafe {
+            parent.activate();
+            assert!(copy_to_user(USER_ADDRESS, b"parent"));
+        }
+        let mut child = parent.try_clone().unwrap();
+        let (frame, flags) = parent.translate(USER_ADDRESS).unwrap();
+        assert_eq!(child.translate(USER_ADDRESS), Some((frame, flags)));
+        assert!(
+            flags.contains(MemoryFlags::COPY_ON_WRITE) && !flags.contains(MemoryFlags::WRITABLE)
+        );
+        assert_eq!(GLOBAL_PAGE_ALLOCATOR.lock().references(frame), 2);
+        // Pages that were never touched stay unmapped in both
+        assert_eq!(child.translate(USER_ADDRESS + PAGE_SIZE), None);
+
+        let mut copied = [0u8; 6];
+        unsafe {
+            // The parent writes first and gets a copy
+            assert!(copy_to_user(USER_ADDRESS, b"PARENT"));
+            child.activate();
+            assert!(copy_from_user(&mut copied, USER_ADDRESS));
+            assert_eq!(&copied, b"parent");
+            // The child is the only one left using the original frame, so it keeps it
+            assert!(copy_to_user(USER_ADDRESS, b"child!"));
+            parent.activate();
+            assert!(copy_from_user(&mut copied, USER_ADDRESS));
+            KERNEL_MEMORY_MAP.lock().load_memory_map();
+        }
+        assert_eq!(&copied, b"PARENT");
+        assert_ne!(parent.translate(USER_ADDRESS).unwrap().0, frame);
+        assert_eq!(
+            child.translate(USER_ADDRESS),
+            Some((frame, MemoryFlags::USER_DATA))
+        );
+        assert_eq!(GLOBAL_PAGE_ALLOCATOR.lock().references(frame), 1);
+        drop(child);
+        drop(parent);
+        assert_eq!(GLOBAL_PAGE_ALLOCATOR.lock().references(frame), 0);
+    }
+}
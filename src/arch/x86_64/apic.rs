@@ -1,42 +1,40 @@
-use crate::bitmap_allocator::GLOBAL_PAGE_ALLOCATOR;
+use crate::bitmap_allocator::PAGE_SIZE;
+use crate::kernel::{
+    mmio::{ioremap, CacheMode},
+    KERNEL_MEMORY_MAP,
+};
 
-use super::idt::{APIC_ERROR_INTERRUPT_ID, APIC_SPURIOUS_INTERRUPT_ID, APIC_TIMER_INTERRUPT_ID};
+use super::{
+    idt::{APIC_ERROR_INTERRUPT_ID, APIC_SPURIOUS_INTERRUPT_ID, APIC_TIMER_INTERRUPT_ID},
+    timer::TIMER_DIVIDE,
+};
 use lazy_static::lazy_static;
 use spin::RwLock;
 
 use core::ops::DerefMut;
 
-use super::paging::active_page_table_mapper;
-use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder, TimerDivide};
-use x86_64::{
-    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
-};
+use x2apic::lapic::{xapic_base, LocalApic, LocalApicBuilder};
 lazy_static! {
     pub static ref LAPIC: RwLock<LocalApic> = {
-        let apic_physical_address: u64 = unsafe { xapic_base() };
-        let apic_virtual_address: u64 = 1024 * 1024 * 1024 * 32;
-        unsafe {
-            active_page_table_mapper()
-                .map_to(
-                    Page::containing_address(VirtAddr::new(apic_virtual_address)),
-                    PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(apic_physical_address)),
-                    PageTableFlags::WRITABLE
-                            | PageTableFlags::PRESENT
-                            | PageTableFlags::USER_ACCESSIBLE,
-                    GLOBAL_PAGE_ALLOCATOR.lock().deref_mut(),
-                )
-                .unwrap()
-                .flush();
-        };
+        // The LAPIC stays mapped forever
+        let apic_registers = unsafe {
+            ioremap(
+                xapic_base() as usize,
+                PAGE_SIZE,
+                CacheMode::Uncached,
+                KERNEL_MEMORY_MAP.lock().deref_mut(),
+            )
+        }
+        .expect("Failed to map the LAPIC registers");
         RwLock::new(LocalApicBuilder::new()
             .timer_vector(APIC_TIMER_INTERRUPT_ID as usize)
             .error_vector(APIC_ERROR_INTERRUPT_ID as usize)
             .spurious_vector(APIC_SPURIOUS_INTERRUPT_ID as usize)
-            .timer_mode(x2apic::lapic::TimerMode::Periodic)
-            .timer_divide(TimerDivide::Div2)
-            .timer_initial(10000)
-            .set_xapic_base(apic_virtual_address)
+            // Stopped until `timer` starts it with a calibrated count
+            .timer_mode(x2apic::lapic::TimerMode::OneShot)
+            .timer_divide(TIMER_DIVIDE)
+            .timer_initial(0)
+            .set_xapic_base(apic_registers.address() as u64)
             .build()
             .unwrap())
     };
@@ -0,0 +1,446 @@
+use core::{
+    arch::global_asm,
+    fmt::{self, Display},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::{control::Cr2, rflags::RFlags},
+    structures::idt::PageFaultErrorCode,
+};
+
+use super::{address_space, idt, smp::MAX_CORES};
+use crate::{
+    kernel::{interrupts::IRQ_COUNT, memory_area::FaultAccess, virtual_memory::KernelStack},
+    multicore::current_core_id,
+};
+
+/// Number of vectors reserved for CPU exceptions
+pub const EXCEPTION_COUNT: usize = 32;
+/// Bytes between the entry stubs of two consecutive vectors
+const STUB_SIZE: usize = 16;
+/// Longest x86 instruction
+const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+
+const EXCEPTION_NAMES: [&str; EXCEPTION_COUNT] = [
+    "Divide Error (#DE)",
+    "Debug (#DB)",
+    "Non-Maskable Interrupt (NMI)",
+    "Breakpoint (#BP)",
+    "Overflow (#OF)",
+    "Bound Range Exceeded (#BR)",
+    "Invalid Opcode (#UD)",
+    "Device Not Available (#NM)",
+    "Double Fault (#DF)",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS (#TS)",
+    "Segment Not Present (#NP)",
+    "Stack-Segment Fault (#SS)",
+    "General Protection Fault (#GP)",
+    "Page Fault (#PF)",
+    "Reserved Exception 15",
+    "x87 Floating-Point Exception (#MF)",
+    "Alignment Check (#AC)",
+    "Machine Check (#MC)",
+    "SIMD Floating-Point Exception (#XM)",
+    "Virtualization Exception (#VE)",
+    "Control Protection Exception (#CP)",
+    "Reserved Exception 22",
+    "Reserved Exception 23",
+    "Reserved Exception 24",
+    "Reserved Exception 25",
+    "Reserved Exception 26",
+    "Reserved Exception 27",
+    "Hypervisor Injection Exception (#HV)",
+    "VMM Communication Exception (#VC)",
+    "Security Exception (#SX)",
+    "Reserved Exception 31",
+];
+
+/// Vectors where the CPU pushes an error code, the entry stubs push a zero for the others
+const fn has_error_code(vector: usize) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Registers of the interrupted code as the entry stubs leave them on the stack
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// 0 for exceptions without one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+impl ExceptionFrame {
+    fn user_mode(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+// Every stub is 16 bytes long so the one of a vector is found without a table
+macro_rules! exception_stubs {
+    ($($vector:literal: $push_error_code:literal),* $(,)?) => {
+        global_asm!(
+            ".global exception_stubs",
+            ".p2align 4",
+            "exception_stubs:",
+            $(
+                ".p2align 4",
+                $push_error_code,
+                concat!("push ", $vector),
+                "jmp exception_common",
+            )*
+            // Interrupts never push an error code
+            ".set vector, 32",
+            ".rept 224",
+            ".p2align 4",
+            "push 0",
+            "push vector",
+            "jmp exception_common",
+            ".set vector, vector + 1",
+            ".endr",
+            "exception_common:",
+            "    push rax",
+            "    push rbx",
+            "    push rcx",
+            "    push rdx",
+            "    push rsi",
+            "    push rdi",
+            "    push rbp",
+            "    push r8",
+            "    push r9",
+            "    push r10",
+            "    push r11",
+            "    push r12",
+            "    push r13",
+            "    push r14",
+            "    push r15",
+            // The CPU aligned the stack before pushing 7 values, 15 more leave it aligned for the call
+            "    mov rdi, rsp",
+            "    cld",
+            "    call {dispatch}",
+            "    pop r15",
+            "    pop r14",
+            "    pop r13",
+            "    pop r12",
+            "    pop r11",
+            "    pop r10",
+            "    pop r9",
+            "    pop r8",
+            "    pop rbp",
+            "    pop rdi",
+            "    pop rsi",
+            "    pop rdx",
+            "    pop rcx",
+            "    pop rbx",
+            "    pop rax",
+            // Vector and error code
+            "    add rsp, 16",
+            "    iretq",
+            dispatch = sym exception_dispatch,
+        );
+    };
+}
+exception_stubs!(
+    0: "push 0", 1: "push 0", 2: "push 0", 3: "push 0", 4: "push 0", 5: "push 0", 6: "push 0", 7: "push 0",
+    8: "", 9: "push 0", 10: "", 11: "", 12: "", 13: "", 14: "", 15: "push 0",
+    16: "push 0", 17: "", 18: "push 0", 19: "push 0", 20: "push 0", 21: "", 22: "push 0", 23: "push 0",
+    24: "push 0", 25: "push 0", 26: "push 0", 27: "push 0", 28: "push 0", 29: "", 30: "", 31: "push 0",
+);
+extern "C" {
+    static exception_stubs: u8;
+}
+
+/// Address the IDT entry of `vector` has to point to
+pub fn stub_address(vector: usize) -> usize {
+    assert!(vector < IRQ_COUNT, "Vector {vector} doesn't exist");
+    core::ptr::addr_of!(exception_stubs) as usize + vector * STUB_SIZE
+}
+
+// Callee saved registers are restored from the original stack, whatever the function did to them
+global_asm!(
+    ".global call_on_stack_raw",
+    "call_on_stack_raw:",
+    "    push rbp",
+    "    mov rbp, rsp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [rsi - 8], rsp",
+    "    lea rsp, [rsi - 16]",
+    "    call rdi",
+    "    mov eax, 1",
+    "call_on_stack_return:",
+    "    mov rsp, [rsp + 8]",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+    // The double fault handler resumes here with the stack pointer the call started with
+    "call_on_stack_fixup:",
+    "    xor eax, eax",
+    "    jmp call_on_stack_return",
+);
+extern "C" {
+    fn call_on_stack_raw(function: extern "C" fn(), stack_top: usize) -> bool;
+    static call_on_stack_fixup: u8;
+}
+
+/// Top of the stack [`call_on_stack`] is running a function on for each core, 0 when there is none
+static RECOVERABLE_STACKS: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(0) }; MAX_CORES];
+
+/// Runs `function` on `stack`, returns false instead of panicking if it overflows the stack
+///
+/// The double fault caused by the overflow is reported and the call is abandoned, like a probe.
+///
+/// # Safety
+/// `function` must not hold locks or leave anything half done when it overflows
+pub unsafe fn call_on_stack(function: extern "C" fn(), stack: &KernelStack) -> bool {
+    let core = current_core_id();
+    RECOVERABLE_STACKS[core].store(stack.top(), Ordering::Relaxed);
+    let completed = call_on_stack_raw(function, stack.top());
+    RECOVERABLE_STACKS[core].store(0, Ordering::Relaxed);
+    completed
+}
+
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        vector if vector >= EXCEPTION_COUNT as u64 => {
+            idt::handle_interrupt(vector as u8);
+            return;
+        }
+        BREAKPOINT => {
+            println!("Breakpoint at 0x{:X}", frame.rip);
+            return;
+        }
+        DOUBLE_FAULT => {
+            let stack_top = RECOVERABLE_STACKS[current_core_id()].load(Ordering::Relaxed);
+            if stack_top != 0 {
+                println!("Recovered from {}", ExceptionReport(frame));
+                frame.rip = core::ptr::addr_of!(call_on_stack_fixup) as u64;
+                frame.rsp = stack_top as u64 - 16;
+                return;
+            }
+        }
+        PAGE_FAULT => {
+            let fault = PageFault::read(frame);
+            if fault.resolve(frame) {
+                return;
+            }
+            panic!("{fault}\n{}", ExceptionReport(frame));
+        }
+        _ => {}
+    }
+    panic!("{}", ExceptionReport(frame));
+}
+
+/// A page fault, read before anything else can fault and replace CR2
+struct PageFault {
+    address: usize,
+    access: FaultAccess,
+}
+impl PageFault {
+    fn read(frame: &ExceptionFrame) -> Self {
+        let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        Self {
+            address: Cr2::read_raw() as usize,
+            access: FaultAccess {
+                write: error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+                execute: error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+                user: error_code.contains(PageFaultErrorCode::USER_MODE),
+                present: error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            },
+        }
+    }
+    /// Maps the page on demand or resumes a probe at its fixup, returns false if the fault is a bug
+    fn resolve(&self, frame: &mut ExceptionFrame) -> bool {
+        // Resolving the fault can take locks held by a core waiting for this one to take its TLB shootdown
+        if RFlags::from_bits_truncate(frame.rflags).contains(RFlags::INTERRUPT_FLAG) {
+            interrupts::enable();
+        }
+        if address_space::handle_page_fault(self.address, self.access) {
+            return true;
+        }
+        if let Some(fixup) = idt::probe_fixup(frame.rip as usize) {
+            frame.rip = fixup as u64;
+            return true;
+        }
+        false
+    }
+}
+impl Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = self.access;
+        write!(
+            f,
+            "Page Fault at 0x{:X}: {} of a {} page in {} mode",
+            self.address,
+            if access.execute {
+                "instruction fetch"
+            } else if access.write {
+                "write"
+            } else {
+                "read"
+            },
+            if access.present {
+                "protected"
+            } else {
+                "missing"
+            },
+            if access.user { "user" } else { "kernel" },
+        )
+    }
+}
+
+/// Describes an exception with the state of the code it interrupted
+pub struct ExceptionReport<'a>(pub &'a ExceptionFrame);
+impl Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.0;
+        let name = EXCEPTION_NAMES
+            .get(frame.vector as usize)
+            .unwrap_or(&"Unknown Exception");
+        writeln!(
+            f,
+            "{name} in {} mode",
+            if frame.user_mode() { "user" } else { "kernel" }
+        )?;
+        if has_error_code(frame.vector as usize) {
+            write!(f, "    Error Code: 0x{:X}", frame.error_code)?;
+            if matches!(frame.vector, 10..=13) && frame.error_code != 0 {
+                write_selector_error(f, frame.error_code)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "    Instruction at 0x{:X}:", frame.rip)?;
+        for offset in 0..MAX_INSTRUCTION_LENGTH {
+            let mut byte = 0;
+            // The instruction pointer itself may be what faulted
+            if !unsafe { idt::probe_read((frame.rip as usize + offset) as *const u8, &mut byte) } {
+                write!(f, " ??")?;
+                break;
+            }
+            write!(f, " {byte:02x}")?;
+        }
+        writeln!(f)?;
+        let registers = [
+            ("RAX", frame.rax),
+            ("RBX", frame.rbx),
+            ("RCX", frame.rcx),
+            ("RDX", frame.rdx),
+            ("RSI", frame.rsi),
+            ("RDI", frame.rdi),
+            ("RBP", frame.rbp),
+            ("RSP", frame.rsp),
+            ("R8 ", frame.r8),
+            ("R9 ", frame.r9),
+            ("R10", frame.r10),
+            ("R11", frame.r11),
+            ("R12", frame.r12),
+            ("R13", frame.r13),
+            ("R14", frame.r14),
+            ("R15", frame.r15),
+        ];
+        for line in registers.chunks(4) {
+            write!(f, "   ")?;
+            for (name, value) in line {
+                write!(f, " {name}={value:016X}")?;
+            }
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "    RIP={:016X} RFLAGS={:016X} CS={:04X} SS={:04X}",
+            frame.rip, frame.rflags, frame.cs, frame.ss
+        )
+    }
+}
+
+/// Decodes the error code of the exceptions caused by a segment selector
+fn write_selector_error(f: &mut fmt::Formatter<'_>, error_code: u64) -> fmt::Result {
+    let table = match (error_code >> 1) & 0b11 {
+        0 => "GDT",
+        2 => "LDT",
+        _ => "IDT",
+    };
+    write!(f, " (selector {} in the {table}", error_code >> 3)?;
+    if error_code & 1 != 0 {
+        write!(f, ", external event")?;
+    }
+    write!(f, ")")
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use super::*;
+
+    #[test(name = "Exception reports decode the vector, error code, instruction and registers")]
+    fn test_exception_report() {
+        // ud2 followed by a nop
+        static CODE: [u8; 3] = [0x0f, 0x0b, 0x90];
+        let frame = ExceptionFrame {
+            vector: 13,
+            error_code: (5 << 3) | 0b010 | 1,
+            rip: CODE.as_ptr() as u64,
+            rax: 0x1234,
+            r15: 0xABCD,
+            cs: 0x28,
+            ..Default::default()
+        };
+        let report = format!("{}", ExceptionReport(&frame));
+        assert!(report.starts_with("General Protection Fault (#GP) in kernel mode"));
+        assert!(report.contains("Error Code: 0x2B (selector 5 in the IDT, external event)"));
+        assert!(report.contains(&format!("Instruction at 0x{:X}: 0f 0b 90", frame.rip)));
+        assert!(report.contains("RAX=0000000000001234"));
+        assert!(report.contains("R15=000000000000ABCD"));
+
+        let frame = ExceptionFrame {
+            vector: 6,
+            rip: 0,
+            cs: 0x33,
+            ..Default::default()
+        };
+        let report = format!("{}", ExceptionReport(&frame));
+        assert!(report.starts_with("Invalid Opcode (#UD) in user mode"));
+        assert!(!report.contains("Error Code"));
+        // Nothing is mapped at 0
+        assert!(report.contains("Instruction at 0x0: ??"));
+    }
+
+    #[test(name = "Execution resumes after a breakpoint")]
+    fn test_breakpoint() {
+        idt::IDT.load();
+        x86_64::instructions::interrupts::int3();
+    }
+}
@@ -0,0 +1,141 @@
+use core::{arch::asm, ops::DerefMut};
+
+use alloc::boxed::Box;
+use x86_64::{
+    instructions::{
+        segmentation::{Segment, CS, DS, ES, SS},
+        tables::load_tss,
+    },
+    registers::segmentation::SegmentSelector,
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable},
+        tss::TaskStateSegment,
+    },
+    PrivilegeLevel, VirtAddr,
+};
+
+use super::idt::IDT;
+use crate::kernel::{virtual_memory::KernelStack, KERNEL_MEMORY_MAP};
+
+/// Interrupt stack table slots, every core has its own stacks for them
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+/// Size of every interrupt stack and of the stack used when an interrupt comes from userspace
+const STACK_PAGES: usize = 4;
+/// Size of the stack every core runs on after [`switch_to_kernel_stack`], as big as the bootloader's
+const KERNEL_STACK_PAGES: usize = 16;
+
+// The layout is the same on every core, user data comes before user code as SYSRET expects
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// Top of a new guarded kernel stack of `pages` pages that is never freed
+fn allocate_stack(pages: usize) -> VirtAddr {
+    let stack = KernelStack::new(pages, KERNEL_MEMORY_MAP.lock().deref_mut())
+        .expect("Failed to allocate a core stack");
+    VirtAddr::new(stack.top() as u64)
+}
+
+/// Loads a new GDT and TSS on the current core, every core must call this once
+///
+/// Double faults, NMIs and machine checks then run on their own stacks, so they are handled
+/// even when the kernel stack overflowed.
+///
+/// # Safety
+/// Nothing may rely on the segments set up by the bootloader anymore
+pub unsafe fn init() {
+    let mut tss = TaskStateSegment::new();
+    for index in [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+    ] {
+        tss.interrupt_stack_table[index as usize] = allocate_stack(STACK_PAGES);
+    }
+    tss.privilege_stack_table[0] = allocate_stack(STACK_PAGES);
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let selectors = [
+        gdt.append(Descriptor::kernel_code_segment()),
+        gdt.append(Descriptor::kernel_data_segment()),
+        gdt.append(Descriptor::user_data_segment()),
+        gdt.append(Descriptor::user_code_segment()),
+        gdt.append(Descriptor::tss_segment(tss)),
+    ];
+    assert_eq!(
+        selectors,
+        [
+            KERNEL_CODE_SELECTOR,
+            KERNEL_DATA_SELECTOR,
+            USER_DATA_SELECTOR,
+            USER_CODE_SELECTOR,
+            TSS_SELECTOR
+        ]
+    );
+    gdt.load();
+    CS::set_reg(KERNEL_CODE_SELECTOR);
+    SS::set_reg(KERNEL_DATA_SELECTOR);
+    DS::set_reg(KERNEL_DATA_SELECTOR);
+    ES::set_reg(KERNEL_DATA_SELECTOR);
+    load_tss(TSS_SELECTOR);
+    // The IST stacks can only be used now that the TSS is loaded
+    IDT.load();
+}
+
+/// Moves the current core onto a new guarded kernel stack and continues with `next`
+///
+/// The stacks of the bootloader have no guard page, so overflowing them would corrupt memory silently.
+///
+/// # Safety
+/// [`init`] must have been called on this core, nothing on the old stack may be used afterwards
+pub unsafe fn switch_to_kernel_stack(next: extern "C" fn() -> !) -> ! {
+    let top = allocate_stack(KERNEL_STACK_PAGES);
+    // The top is page aligned, so the stack is aligned as the call expects
+    asm!(
+        "mov rsp, {top}",
+        "xor ebp, ebp",
+        "call {next}",
+        top = in(reg) top.as_u64(),
+        next = in(reg) next,
+        options(noreturn)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use core::hint::black_box;
+
+    use super::*;
+    use crate::arch::x86_64::exceptions::call_on_stack;
+
+    #[allow(unconditional_recursion)]
+    extern "C" fn overflow() {
+        let mut buffer = [0u8; 512];
+        black_box(&mut buffer);
+        overflow();
+        black_box(&mut buffer);
+    }
+
+    extern "C" fn fits() {
+        black_box([0u8; 512]);
+    }
+
+    #[test(name = "Overflowing a kernel stack ends in a double fault on its own stack")]
+    fn test_stack_overflow() {
+        assert_eq!(CS::get_reg(), KERNEL_CODE_SELECTOR);
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        let stack = KernelStack::new(STACK_PAGES, mapper.deref_mut()).unwrap();
+        drop(mapper);
+        unsafe {
+            assert!(call_on_stack(fits, &stack));
+            // The guard page below the stack faults, and the page fault can't be pushed on the stack either
+            assert!(!call_on_stack(overflow, &stack));
+            stack.destroy(KERNEL_MEMORY_MAP.lock().deref_mut());
+        }
+    }
+}
@@ -1,51 +1,191 @@
+use core::{arch::global_asm, ptr::addr_of};
+
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{
+    structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable},
+    VirtAddr,
+};
 
-use crate::arch::x86_64::apic::LAPIC;
+use super::{
+    exceptions::{self, EXCEPTION_COUNT},
+    gdt,
+    pic::PIC_SPURIOUS_VECTORS,
+    timer, tlb,
+};
+use crate::{
+    arch::x86_64::apic::LAPIC,
+    kernel::interrupts::{self, IRQ_COUNT},
+};
 
 pub const APIC_TIMER_INTERRUPT_ID: u8 = 200;
 pub const APIC_ERROR_INTERRUPT_ID: u8 = 201;
 pub const APIC_SPURIOUS_INTERRUPT_ID: u8 = 202;
-lazy_static! {
-    pub static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.general_protection_fault
-            .set_handler_fn(gpf_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_interrupt_handler);
-        idt[APIC_TIMER_INTERRUPT_ID].set_handler_fn(on_timer_pulse);
-        idt[APIC_ERROR_INTERRUPT_ID].set_handler_fn(on_apic_error);
-        idt[APIC_SPURIOUS_INTERRUPT_ID].set_handler_fn(on_apic_spurious_interrupt);
-        idt
+pub const TLB_SHOOTDOWN_INTERRUPT_ID: u8 = 203;
+pub const WAKE_UP_INTERRUPT_ID: u8 = 204;
+/// Builds an IDT where every vector goes through its stub
+///
+/// With `ist`, the exceptions that can hit with a broken stack get their own from the TSS of the core.
+fn build_idt(ist: bool) -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    // Every exception goes through the stubs, which save the registers for the report
+    let stub = |vector| VirtAddr::new(exceptions::stub_address(vector) as u64);
+    // The reserved vectors have no named entry, and every entry has the same layout
+    let entries = unsafe {
+        &mut *(&mut idt as *mut InterruptDescriptorTable)
+            .cast::<[Entry<HandlerFunc>; EXCEPTION_COUNT]>()
     };
+    for (vector, entry) in entries.iter_mut().enumerate() {
+        unsafe { entry.set_handler_addr(stub(vector)) };
+    }
+    for (vector, stack_index) in [
+        (2, gdt::NMI_IST_INDEX),
+        (8, gdt::DOUBLE_FAULT_IST_INDEX),
+        (18, gdt::MACHINE_CHECK_IST_INDEX),
+    ]
+    .into_iter()
+    .filter(|_| ist)
+    {
+        unsafe {
+            entries[vector]
+                .set_handler_addr(stub(vector))
+                .set_stack_index(stack_index)
+        };
+    }
+    // The others dispatch into the handlers registered with `interrupts::register`
+    for vector in EXCEPTION_COUNT..IRQ_COUNT {
+        unsafe { idt[vector as u8].set_handler_addr(stub(vector)) };
+    }
+    idt
+}
+lazy_static! {
+    /// Used until the core has a TSS, an IST index without one would turn the exception into a triple fault
+    static ref BOOT_IDT: InterruptDescriptorTable = build_idt(false);
+    /// Loaded by [`gdt::init`] once the TSS of the core has the IST stacks
+    pub static ref IDT: InterruptDescriptorTable = build_idt(true);
 }
 
-extern "x86-interrupt" fn on_apic_spurious_interrupt(stack_frame: InterruptStackFrame) {
-    println!("Apic Spurious Interrupt {stack_frame:#?}");
-    unsafe { LAPIC.write().end_of_interrupt() }
+/// Loads the boot IDT on the current core, every core must call this before anything can fault on it
+///
+/// Every exception runs on the current stack until [`gdt::init`] switches the core to [`IDT`].
+pub fn init() {
+    BOOT_IDT.load();
 }
-extern "x86-interrupt" fn on_apic_error(stack_frame: InterruptStackFrame) {
-    println!("Apic error {stack_frame:#?}");
-    unsafe { LAPIC.write().end_of_interrupt() }
+
+/// Registers the handlers of the interrupts the kernel uses itself, must be called once before the LAPIC is enabled
+pub fn register_system_handlers() {
+    interrupts::register(APIC_TIMER_INTERRUPT_ID.into(), timer::on_timer_interrupt);
+    interrupts::register(APIC_ERROR_INTERRUPT_ID.into(), || {
+        let status = unsafe { LAPIC.read().error_flags() };
+        println!("Apic error {status:?}");
+    });
+    interrupts::register(APIC_SPURIOUS_INTERRUPT_ID.into(), || {
+        println!("Apic Spurious Interrupt");
+    });
+    interrupts::register(TLB_SHOOTDOWN_INTERRUPT_ID.into(), tlb::process_shootdowns);
+    // Only there to end the `hlt` of an idle core
+    interrupts::register(WAKE_UP_INTERRUPT_ID.into(), || {});
 }
-extern "x86-interrupt" fn on_timer_pulse(_stack_frame: InterruptStackFrame) {
-    unsafe { LAPIC.write().end_of_interrupt() }
+
+/// Called by the entry stubs for every vector that isn't an exception
+pub fn handle_interrupt(vector: u8) {
+    if !interrupts::dispatch(vector.into()) {
+        println!("Unhandled interrupt {vector}");
+    }
+    // The LAPIC doesn't wait for an EOI of a spurious interrupt, and those of the PICs never went through it
+    if vector != APIC_SPURIOUS_INTERRUPT_ID && !PIC_SPURIOUS_VECTORS.contains(&vector) {
+        unsafe { LAPIC.write().end_of_interrupt() }
+    }
 }
 
-extern "x86-interrupt" fn page_fault_interrupt_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    panic!(
-        "Page Fault:
-    Error Code: {error_code:#?}
-    Stack Frame: {stack_frame:#?}"
-    );
+// The access is the only instruction that may fault, the handler resumes at the fixup which returns false
+global_asm!(
+    ".global probe_write, probe_write_access, probe_write_fixup",
+    "probe_write:",
+    "probe_write_access:",
+    "    mov byte ptr [rdi], sil",
+    "    mov eax, 1",
+    "    ret",
+    "probe_write_fixup:",
+    "    xor eax, eax",
+    "    ret",
+    ".global probe_read, probe_read_access, probe_read_fixup",
+    "probe_read:",
+    "probe_read_access:",
+    "    mov al, byte ptr [rdi]",
+    "    mov byte ptr [rsi], al",
+    "    mov eax, 1",
+    "    ret",
+    "probe_read_fixup:",
+    "    xor eax, eax",
+    "    ret",
+);
+extern "C" {
+    /// Writes `value` at `address`, returns false instead of panicking if the write page faults
+    pub fn probe_write(address: *mut u8, value: u8) -> bool;
+    /// Reads the byte at `address` into `value`, returns false instead of panicking if the read page faults
+    pub fn probe_read(address: *const u8, value: &mut u8) -> bool;
+}
+extern "C" {
+    static probe_write_access: u8;
+    static probe_write_fixup: u8;
+    static probe_read_access: u8;
+    static probe_read_fixup: u8;
 }
 
-extern "x86-interrupt" fn gpf_interrupt_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
-        "General Protection Fault:
-    Error Code: {error_code:#?}
-    Stack Frame: {stack_frame:#?}"
-    );
+/// Where to resume when `instruction_pointer` is the access of a probe that faulted
+pub fn probe_fixup(instruction_pointer: usize) -> Option<usize> {
+    [
+        (addr_of!(probe_write_access), addr_of!(probe_write_fixup)),
+        (addr_of!(probe_read_access), addr_of!(probe_read_fixup)),
+    ]
+    .into_iter()
+    .find(|&(access, _)| access as usize == instruction_pointer)
+    .map(|(_, fixup)| fixup as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::{
+        hint::spin_loop,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    #[test(name = "Every exception vector, reserved ones included, goes to its stub")]
+    fn test_exception_entries() {
+        for idt in [&*BOOT_IDT, &*IDT] {
+            let entries = unsafe {
+                &*(idt as *const InterruptDescriptorTable)
+                    .cast::<[Entry<HandlerFunc>; EXCEPTION_COUNT]>()
+            };
+            for (vector, entry) in entries.iter().enumerate() {
+                assert_eq!(
+                    entry.handler_addr().as_u64() as usize,
+                    exceptions::stub_address(vector)
+                );
+            }
+        }
+    }
+
+    #[test(name = "Interrupts sent to a registered vector reach its handler")]
+    fn test_registered_vector() {
+        let irq = interrupts::allocate_irq().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let handler_count = count.clone();
+        let handler = interrupts::register(irq, move || {
+            handler_count.fetch_add(1, Ordering::Relaxed);
+        });
+        for expected in 1..=3 {
+            x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+                LAPIC.write().send_ipi_self(irq as u8)
+            });
+            while count.load(Ordering::Relaxed) != expected {
+                spin_loop();
+            }
+        }
+        assert!(interrupts::unregister(handler));
+        interrupts::free_irq(irq);
+    }
 }
@@ -0,0 +1,249 @@
+use core::ops::DerefMut;
+
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+
+use super::{
+    acpi::{self, MadtEntry},
+    pic,
+};
+use crate::{
+    bitmap_allocator::PAGE_SIZE,
+    kernel::{
+        interrupts::Irq,
+        mmio::{ioremap, CacheMode, IoMapping},
+        KERNEL_MEMORY_MAP,
+    },
+};
+
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_WINDOW: usize = 0x10;
+const VERSION_REGISTER: u32 = 0x01;
+/// Every redirection entry takes 2 registers starting at this one
+const FIRST_REDIRECTION_REGISTER: u32 = 0x10;
+
+const MASKED: u64 = 1 << 16;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const ACTIVE_LOW: u64 = 1 << 13;
+const DESTINATION_SHIFT: u64 = 56;
+
+/// Only ISA IRQs can be overridden
+const ISA_BUS: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// A global system interrupt with the signal the device uses for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptLine {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+impl InterruptLine {
+    /// Line of an ISA IRQ, following the interrupt source overrides of the MADT
+    ///
+    /// ISA IRQs are edge triggered and active high unless the firmware says otherwise.
+    pub fn isa(irq: u8) -> Self {
+        let isa = Self {
+            gsi: irq.into(),
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        };
+        let overridden = madt_entries().iter().find_map(|entry| match *entry {
+            MadtEntry::InterruptSourceOverride {
+                bus: ISA_BUS,
+                source,
+                gsi,
+                flags,
+            } if source == irq => Some((gsi, flags)),
+            _ => None,
+        });
+        let Some((gsi, flags)) = overridden else {
+            return isa;
+        };
+        // Both fields are 0 when the line conforms to the bus
+        Self {
+            gsi,
+            polarity: match flags & 0b11 {
+                0b11 => Polarity::ActiveLow,
+                _ => isa.polarity,
+            },
+            trigger_mode: match (flags >> 2) & 0b11 {
+                0b11 => TriggerMode::Level,
+                _ => isa.trigger_mode,
+            },
+        }
+    }
+}
+
+struct IoApic {
+    registers: IoMapping,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write(REGISTER_SELECT, register);
+        self.registers.read(REGISTER_WINDOW)
+    }
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write(REGISTER_SELECT, register);
+        self.registers.write(REGISTER_WINDOW, value);
+    }
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_entries).contains(&gsi)
+    }
+    fn read_entry(&self, gsi: u32) -> u64 {
+        let register = FIRST_REDIRECTION_REGISTER + (gsi - self.gsi_base) * 2;
+        self.read(register) as u64 | ((self.read(register + 1) as u64) << 32)
+    }
+    fn write_entry(&self, gsi: u32, entry: u64) {
+        let register = FIRST_REDIRECTION_REGISTER + (gsi - self.gsi_base) * 2;
+        // Masked while the entry is half written
+        self.write(register, MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+static MADT_ENTRIES: Once<Vec<MadtEntry>> = Once::new();
+static IO_APICS: Once<Vec<Mutex<IoApic>>> = Once::new();
+
+fn madt_entries() -> &'static [MadtEntry] {
+    MADT_ENTRIES.call_once(acpi::madt_entries)
+}
+
+/// Runs `f` with the I/O APIC that handles `gsi`
+fn with_io_apic<R>(gsi: u32, f: impl FnOnce(&IoApic) -> R) -> R {
+    let io_apics = IO_APICS.get().expect("The I/O APICs aren't initialized");
+    // A handler masking its line on this core would wait forever for the lock
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let io_apic = io_apics
+            .iter()
+            .map(|io_apic| io_apic.lock())
+            .find(|io_apic| io_apic.handles(gsi))
+            .unwrap_or_else(|| panic!("No I/O APIC handles GSI {gsi}"));
+        f(&io_apic)
+    })
+}
+
+/// Maps every I/O APIC from the MADT with all of its lines masked and disables the PICs
+///
+/// # Safety
+/// Must be called once, before interrupts are routed
+pub unsafe fn init() {
+    IO_APICS.call_once(|| {
+        let io_apics: Vec<_> = madt_entries()
+            .iter()
+            .filter_map(|entry| match *entry {
+                MadtEntry::IoApic {
+                    address, gsi_base, ..
+                } => Some((address, gsi_base)),
+                _ => None,
+            })
+            .map(|(address, gsi_base)| {
+                // The I/O APICs stay mapped forever
+                let registers = ioremap(
+                    address as usize,
+                    PAGE_SIZE,
+                    CacheMode::Uncached,
+                    KERNEL_MEMORY_MAP.lock().deref_mut(),
+                )
+                .expect("Failed to map the I/O APIC registers");
+                let mut io_apic = IoApic {
+                    registers,
+                    gsi_base,
+                    redirection_entries: 0,
+                };
+                io_apic.redirection_entries = ((io_apic.read(VERSION_REGISTER) >> 16) & 0xFF) + 1;
+                for gsi in gsi_base..gsi_base + io_apic.redirection_entries {
+                    io_apic.write_entry(gsi, MASKED);
+                }
+                Mutex::new(io_apic)
+            })
+            .collect();
+        assert!(!io_apics.is_empty(), "The MADT has no I/O APIC");
+        io_apics
+    });
+    pic::disable();
+}
+
+/// Sends interrupts of `line` to `vector` on the LAPIC of `core`, the line stays masked until [`unmask`]
+pub fn route(line: InterruptLine, vector: Irq, core: usize) {
+    assert!(
+        (0x10..=0xFE).contains(&vector),
+        "Vector {vector} can't be used by the I/O APIC"
+    );
+    let destination = u8::try_from(core).expect("The I/O APIC can only reach LAPIC IDs below 256");
+    let mut entry = vector as u64 | MASKED | ((destination as u64) << DESTINATION_SHIFT);
+    if line.polarity == Polarity::ActiveLow {
+        entry |= ACTIVE_LOW;
+    }
+    if line.trigger_mode == TriggerMode::Level {
+        entry |= LEVEL_TRIGGERED;
+    }
+    with_io_apic(line.gsi, |io_apic| io_apic.write_entry(line.gsi, entry));
+}
+
+/// Routes an ISA IRQ like [`route`] and returns the line it is connected to
+pub fn route_isa(irq: u8, vector: Irq, core: usize) -> InterruptLine {
+    let line = InterruptLine::isa(irq);
+    route(line, vector, core);
+    line
+}
+
+pub fn mask(gsi: u32) {
+    with_io_apic(gsi, |io_apic| {
+        io_apic.write_entry(gsi, io_apic.read_entry(gsi) | MASKED)
+    });
+}
+
+pub fn unmask(gsi: u32) {
+    with_io_apic(gsi, |io_apic| {
+        io_apic.write_entry(gsi, io_apic.read_entry(gsi) & !MASKED)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        hint::spin_loop,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        arch::x86_64::pit::{self, PIT_IRQ},
+        kernel::interrupts::{allocate_irq, free_irq, register, unregister},
+        multicore::current_core_id,
+    };
+
+    #[test(name = "The PIT interrupt goes through the I/O APIC to the chosen core")]
+    fn test_route_pit() {
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+        assert!(pic::masked());
+        let vector = allocate_irq().unwrap();
+        let handler = register(vector, || {
+            TICKS.fetch_add(1, Ordering::Relaxed);
+        });
+        let line = route_isa(PIT_IRQ, vector, current_core_id());
+        assert_eq!(line.trigger_mode, TriggerMode::Edge);
+        pit::start_periodic(100);
+        unmask(line.gsi);
+        while TICKS.load(Ordering::Relaxed) < 3 {
+            spin_loop();
+        }
+        mask(line.gsi);
+        unregister(handler);
+        free_irq(vector);
+    }
+}
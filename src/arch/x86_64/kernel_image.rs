@@ -0,0 +1,129 @@
+use core::{ops::Range, ptr::addr_of};
+
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{OffsetPageTable, PageTableIndex},
+    VirtAddr,
+};
+
+use super::{
+    address_space::{allocate_page_table, page_table},
+    paging::X86MemoryMap,
+};
+use crate::{
+    bitmap_allocator::PAGE_SIZE,
+    kernel::{
+        memory_map::{MemoryFlags, MemoryMap, PageSize},
+        KERNEL_MEMORY_MAP,
+    },
+    limine::{HHDM, KERNEL_ADDRESS},
+};
+
+// Exported by the linker script
+extern "C" {
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// Page aligned range covering everything between the two symbols
+fn section_range(start: *const u8, end: *const u8) -> Range<usize> {
+    (start as usize).div_floor(PAGE_SIZE) * PAGE_SIZE..(end as usize).next_multiple_of(PAGE_SIZE)
+}
+
+/// Pages holding the read only data of the kernel, including the ELF headers
+pub fn rodata_range() -> Range<usize> {
+    section_range(addr_of!(__rodata_start), addr_of!(__rodata_end))
+}
+/// Pages holding the code of the kernel
+pub fn text_range() -> Range<usize> {
+    section_range(addr_of!(__text_start), addr_of!(__text_end))
+}
+/// Pages holding the writable data of the kernel, `.bss` included
+pub fn data_range() -> Range<usize> {
+    section_range(addr_of!(__data_start), addr_of!(__data_end))
+}
+
+/// Switches to a new kernel page table where no section of the kernel image is both writable and executable
+///
+/// Everything outside the kernel image is shared with the page table set up by the bootloader.
+///
+/// # Safety
+/// Must be called once on the bootstrap core, before any other core loads [`KERNEL_MEMORY_MAP`]
+pub unsafe fn remap_kernel() {
+    let kernel_address = KERNEL_ADDRESS.get_response().unwrap();
+    let virtual_base = kernel_address.virtual_base() as usize;
+    let physical_base = kernel_address.physical_base() as usize;
+    let kernel_entry = VirtAddr::new(virtual_base as u64).p4_index();
+
+    let mut kernel_memory_map = KERNEL_MEMORY_MAP.lock();
+    let frame = allocate_page_table().expect("Not enough memory for the kernel page table");
+    let table = page_table(frame);
+    for (index, (entry, old_entry)) in table
+        .iter_mut()
+        .zip(kernel_memory_map.level_4_table().iter())
+        .enumerate()
+    {
+        if PageTableIndex::new(index as u16) != kernel_entry {
+            *entry = old_entry.clone();
+        }
+    }
+    let offset = VirtAddr::new(HHDM.get_response().unwrap().offset());
+    let mut memory_map = X86MemoryMap::new(OffsetPageTable::new(table, offset), frame);
+    for (range, flags) in [
+        (rodata_range(), MemoryFlags::NO_EXECUTE),
+        (text_range(), MemoryFlags::empty()),
+        (data_range(), MemoryFlags::KERNEL_DATA),
+    ] {
+        let physical_address = physical_base + (range.start - virtual_base);
+        assert!(
+            memory_map.map_range(range, physical_address, flags, PageSize::Size4KiB),
+            "Failed to remap the kernel image"
+        );
+    }
+    // Read only pages are otherwise still writable from the kernel
+    Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    memory_map.load_memory_map();
+    *kernel_memory_map = memory_map;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::x86_64::idt::{probe_write, IDT};
+
+    static mut WRITABLE_BYTE: u8 = 0;
+
+    #[test(name = "Kernel sections are mapped with W^X permissions")]
+    fn test_kernel_section_permissions() {
+        let mapper = KERNEL_MEMORY_MAP.lock();
+        for (range, flags) in [
+            (rodata_range(), MemoryFlags::NO_EXECUTE),
+            (text_range(), MemoryFlags::empty()),
+            (data_range(), MemoryFlags::KERNEL_DATA),
+        ] {
+            for page in range.step_by(PAGE_SIZE) {
+                let (_, page_flags) = mapper.translate(page).unwrap();
+                assert_eq!(
+                    page_flags & (MemoryFlags::WRITABLE | MemoryFlags::NO_EXECUTE),
+                    flags
+                );
+            }
+        }
+    }
+
+    #[test(name = "Writing to the kernel code page faults")]
+    fn test_text_is_read_only() {
+        IDT.load();
+        let code = remap_kernel as *mut u8;
+        assert!(text_range().contains(&(code as usize)));
+        unsafe {
+            assert!(!probe_write(code, code.read_volatile()));
+            assert!(probe_write(addr_of!(WRITABLE_BYTE).cast_mut(), 0x42));
+            assert_eq!(addr_of!(WRITABLE_BYTE).read_volatile(), 0x42);
+        }
+    }
+}
@@ -1,15 +1,59 @@
-use core::ops::DerefMut;
+use core::{
+    ops::{DerefMut, Range},
+    sync::atomic::Ordering,
+};
 
 use crate::{
-    bitmap_allocator::{BitmapAllocator, GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE}, kernel::memory_map::{MemoryFlags, MemoryMap}, limine::HHDM
+    bitmap_allocator::{BitmapAllocator, PageAllocator, GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+    buddy_allocator::BuddyAllocator,
+    kernel::{
+        memory_map::{physical_to_virtual, MemoryFlags, MemoryMap, PageSize},
+        memory_stats::PAGE_TABLE_PAGES,
+    },
+    limine::HHDM,
 };
+use raw_cpuid::CpuId;
 use x86_64::{
-    registers::control::{Cr3, Cr3Flags}, structures::paging::{
-        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB,
-    }, PhysAddr, VirtAddr
+    registers::{control::Cr3, model_specific::Msr},
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize as X86PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
 };
 
+use super::tlb::{self, TlbBatch, SHARED_PCID};
+
+const IA32_PAT: u32 = 0x277;
+/// Memory types of the PAT entries, same as the power on defaults except for entry 4 which is write-combining
+///
+/// Entries 0 to 3 are write-back, write-through, uncached minus and uncached, so `WRITE_THROUGH` and
+/// `NO_CACHE` keep their usual meaning.
+const PAT_MEMORY_TYPES: u64 = 0x0007_0401_0007_0406;
+/// In level 1 entries bit 7 selects the upper half of the PAT instead of marking a huge page
+const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+/// Available to software, marks the entries of [`MemoryFlags::COPY_ON_WRITE`] pages
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Bits of a level 1 entry that are controlled by [`MemoryFlags`], the others are kept when changing the flags
+const MEMORY_FLAGS_MASK: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::NO_EXECUTE)
+    .union(PAT_4KIB)
+    .union(COPY_ON_WRITE);
+
+/// Programs the PAT so [`MemoryFlags::WRITE_COMBINING`] can be used, every core must call this
+///
+/// # Safety
+/// Must be called before anything is mapped with [`MemoryFlags::WRITE_COMBINING`]
+pub unsafe fn init_pat() {
+    Msr::new(IA32_PAT).write(PAT_MEMORY_TYPES);
+}
+
 unsafe impl<'a> FrameAllocator<Size4KiB> for BitmapAllocator<'a> {
     fn allocate_frame(&mut self) -> Option<x86_64::structures::paging::PhysFrame<Size4KiB>> {
         Some(PhysFrame::containing_address(PhysAddr::new(
@@ -22,10 +66,147 @@ impl<'a> FrameDeallocator<Size4KiB> for BitmapAllocator<'a> {
         self.free_pages(frame.start_address().as_u64() as usize, PAGE_SIZE);
     }
 }
-pub struct X86MemoryMap<M: Mapper<Size4KiB> + Send>(M, PhysFrame<Size4KiB>);
-impl<M: Mapper<Size4KiB> + Send> X86MemoryMap<M> {
+unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<x86_64::structures::paging::PhysFrame<Size4KiB>> {
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            self.request_and_clear_page()?.get() as u64,
+        )))
+    }
+}
+impl FrameDeallocator<Size4KiB> for BuddyAllocator<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free_pages(frame.start_address().as_u64() as usize, PAGE_SIZE);
+    }
+}
+/// Hands out frames to the mapper, which only asks for frames to build new page tables
+///
+/// This keeps [`PAGE_TABLE_PAGES`] up to date.
+pub struct PageTableFrameAllocator<'a>(pub &'a mut PageAllocator);
+unsafe impl FrameAllocator<Size4KiB> for PageTableFrameAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.0.allocate_frame()?;
+        PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+        Some(frame)
+    }
+}
+impl FrameDeallocator<Size4KiB> for PageTableFrameAllocator<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.0.deallocate_frame(frame);
+        PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+/// Every page size the x86_64 mapper can map
+pub trait X86Mapper:
+    Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate + Send
+{
+}
+impl<M: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate + Send> X86Mapper for M {}
+
+/// Page tables of one address space, with the PCID its translations are cached with
+pub struct X86MemoryMap<M: X86Mapper>(M, PhysFrame<Size4KiB>, u16);
+impl<M: X86Mapper> X86MemoryMap<M> {
     pub unsafe fn new(mapper: M, addr: PhysFrame<Size4KiB>) -> Self {
-        Self(mapper, addr)
+        Self::with_pcid(mapper, addr, SHARED_PCID)
+    }
+    /// Memory map tagged with a PCID from [`tlb::allocate_pcid`]
+    ///
+    /// # Safety
+    /// No other memory map may use the same PCID, unless it is [`SHARED_PCID`]
+    pub unsafe fn with_pcid(mapper: M, addr: PhysFrame<Size4KiB>, pcid: u16) -> Self {
+        Self(mapper, addr, pcid)
+    }
+    pub fn pcid(&self) -> u16 {
+        self.2
+    }
+    unsafe fn map_sized<S: X86PageSize>(
+        &mut self,
+        from: usize,
+        to: usize,
+        flags: PageTableFlags,
+    ) -> bool
+    where
+        M: Mapper<S>,
+    {
+        Mapper::<S>::map_to(
+            &mut self.0,
+            Page::containing_address(VirtAddr::new(from as u64)),
+            PhysFrame::containing_address(PhysAddr::new(to as u64)),
+            flags,
+            &mut PageTableFrameAllocator(GLOBAL_PAGE_ALLOCATOR.lock().deref_mut()),
+        )
+        .map(|f| f.flush())
+        .is_ok()
+    }
+    unsafe fn unmap_sized<S: X86PageSize>(
+        &mut self,
+        from: usize,
+        batch: &mut TlbBatch,
+    ) -> Option<usize>
+    where
+        M: Mapper<S>,
+    {
+        let (frame, flush) = Mapper::<S>::unmap(
+            &mut self.0,
+            Page::containing_address(VirtAddr::new(from as u64)),
+        )
+        .ok()?;
+        flush.ignore();
+        batch.push(from);
+        Some(frame.start_address().as_u64() as usize)
+    }
+    unsafe fn update_flags_sized<S: X86PageSize>(
+        &mut self,
+        from: usize,
+        flags: PageTableFlags,
+        batch: &mut TlbBatch,
+    ) -> bool
+    where
+        M: Mapper<S>,
+    {
+        Mapper::<S>::update_flags(
+            &mut self.0,
+            Page::containing_address(VirtAddr::new(from as u64)),
+            flags,
+        )
+        .map(|f| {
+            f.ignore();
+            batch.push(from);
+        })
+        .is_ok()
+    }
+    /// Level 1 entry of the page containing `from`, None if it is inside a huge page or no table covers it
+    unsafe fn level_1_entry(&mut self, from: usize) -> Option<&mut PageTableEntry> {
+        let address = VirtAddr::new(from as u64);
+        let table_at = |frame: PhysFrame| {
+            physical_to_virtual(frame.start_address().as_u64() as usize) as *mut PageTable
+        };
+        let mut table = &mut *table_at(self.1);
+        for index in [address.p4_index(), address.p3_index(), address.p2_index()] {
+            // Fails for entries that aren't present and for huge pages
+            table = &mut *table_at(table[index].frame().ok()?);
+        }
+        Some(&mut table[address.p1_index()])
+    }
+    /// Unmaps the page containing `from`, its translation is invalidated once `batch` is flushed
+    unsafe fn unmap_page(&mut self, from: usize, batch: &mut TlbBatch) -> Option<usize> {
+        let TranslateResult::Mapped { frame, flags, .. } =
+            self.0.translate(VirtAddr::new(from as u64))
+        else {
+            return None;
+        };
+        match frame {
+            MappedFrame::Size4KiB(_) => {
+                // The mapper takes a level 1 entry with the PAT bit for a huge page, so it has to be cleared first
+                if flags.contains(PAT_4KIB)
+                    && !self.update_flags_sized::<Size4KiB>(from, flags - PAT_4KIB, batch)
+                {
+                    return None;
+                }
+                self.unmap_sized::<Size4KiB>(from, batch)
+            }
+            MappedFrame::Size2MiB(_) => self.unmap_sized::<Size2MiB>(from, batch),
+            MappedFrame::Size1GiB(_) => self.unmap_sized::<Size1GiB>(from, batch),
+        }
     }
 }
 impl<'a> X86MemoryMap<OffsetPageTable<'a>> {
@@ -36,6 +217,13 @@ impl<'a> X86MemoryMap<OffsetPageTable<'a>> {
             Self::new(OffsetPageTable::new(active_table, offset), Cr3::read().0)
         }
     }
+    pub fn level_4_table(&mut self) -> &mut PageTable {
+        self.0.level_4_table_mut()
+    }
+    /// Frame holding the level 4 table, which is loaded into CR3 by [`MemoryMap::load_memory_map`]
+    pub fn level_4_frame(&self) -> PhysFrame<Size4KiB> {
+        self.1
+    }
 }
 impl From<MemoryFlags> for PageTableFlags {
     fn from(value: MemoryFlags) -> Self {
@@ -55,31 +243,217 @@ impl From<MemoryFlags> for PageTableFlags {
         if value.contains(MemoryFlags::NO_EXECUTE) {
             x86_flags |= PageTableFlags::NO_EXECUTE;
         }
+        if value.contains(MemoryFlags::COPY_ON_WRITE) {
+            x86_flags |= COPY_ON_WRITE;
+        }
+        if value.contains(MemoryFlags::WRITE_COMBINING) {
+            // Selects PAT entry 4, PAT_4KIB is set separately since the mapper refuses to map pages with it
+            x86_flags -= PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+        }
         x86_flags
     }
 }
-unsafe impl<M: Mapper<Size4KiB> + Send> MemoryMap for X86MemoryMap<M> {
-    unsafe fn map_memory(&mut self, from: usize, to: usize, flags: MemoryFlags) -> bool {
+impl From<PageTableFlags> for MemoryFlags {
+    /// Converts the flags of a level 1 entry, where bit 7 selects the PAT entry
+    fn from(value: PageTableFlags) -> Self {
+        let mut flags = MemoryFlags::empty();
+        if value.contains(PageTableFlags::WRITABLE) {
+            flags |= MemoryFlags::WRITABLE;
+        }
+        if value.contains(PageTableFlags::USER_ACCESSIBLE) {
+            flags |= MemoryFlags::USER_ACCESSIBLE;
+        }
+        if value.contains(PageTableFlags::NO_EXECUTE) {
+            flags |= MemoryFlags::NO_EXECUTE;
+        }
+        if value.contains(COPY_ON_WRITE) {
+            flags |= MemoryFlags::COPY_ON_WRITE;
+        }
+        if value.contains(PAT_4KIB) {
+            // PAT entry 4 is the only one from the upper half being used
+            flags |= MemoryFlags::WRITE_COMBINING;
+        } else {
+            if value.contains(PageTableFlags::WRITE_THROUGH) {
+                flags |= MemoryFlags::WRITE_THROUGH;
+            }
+            if value.contains(PageTableFlags::NO_CACHE) {
+                flags |= MemoryFlags::NO_CACHE;
+            }
+        }
+        flags
+    }
+}
+unsafe impl<M: X86Mapper> MemoryMap for X86MemoryMap<M> {
+    unsafe fn map_page(
+        &mut self,
+        from: usize,
+        to: usize,
+        size: PageSize,
+        flags: MemoryFlags,
+    ) -> bool {
+        assert!(
+            from % size.size() == 0 && to % size.size() == 0,
+            "Pages must be aligned to their size"
+        );
+        // Huge pages keep the PAT bit somewhere else, and not every CPU has 1 GiB pages,
+        // so these are mapped with smaller pages instead
+        let split_into = match size {
+            PageSize::Size1GiB
+                if flags.contains(MemoryFlags::WRITE_COMBINING) || !has_1gib_pages() =>
+            {
+                Some(PageSize::Size2MiB)
+            }
+            PageSize::Size2MiB if flags.contains(MemoryFlags::WRITE_COMBINING) => {
+                Some(PageSize::Size4KiB)
+            }
+            _ => None,
+        };
+        if let Some(smaller_size) = split_into {
+            return self.map_range(from..from + size.size(), to, flags, smaller_size);
+        }
+        match size {
+            PageSize::Size4KiB => {
+                if !self.map_sized::<Size4KiB>(from, to, flags.into()) {
+                    return false;
+                }
+                if flags.contains(MemoryFlags::WRITE_COMBINING) {
+                    let mut batch = TlbBatch::new(self.2);
+                    let updated = self.update_flags_sized::<Size4KiB>(
+                        from,
+                        PageTableFlags::from(flags) | PAT_4KIB,
+                        &mut batch,
+                    );
+                    batch.flush();
+                    return updated;
+                }
+                true
+            }
+            PageSize::Size2MiB => self.map_sized::<Size2MiB>(from, to, flags.into()),
+            PageSize::Size1GiB => self.map_sized::<Size1GiB>(from, to, flags.into()),
+        }
+    }
 
-        return self.0.map_to(
-                Page::containing_address(VirtAddr::new(from as u64)),
-                PhysFrame::containing_address(PhysAddr::new(to as u64)),
-                flags.into(),
-                GLOBAL_PAGE_ALLOCATOR.lock().deref_mut(),
-            )
-            .map(|f| f.flush())
-            .is_ok();
+    fn page_size(&self, from: usize) -> Option<PageSize> {
+        let TranslateResult::Mapped { frame, .. } = self.0.translate(VirtAddr::new(from as u64))
+        else {
+            return None;
+        };
+        Some(match frame {
+            MappedFrame::Size4KiB(_) => PageSize::Size4KiB,
+            MappedFrame::Size2MiB(_) => PageSize::Size2MiB,
+            MappedFrame::Size1GiB(_) => PageSize::Size1GiB,
+        })
     }
 
-    unsafe fn unmap_memory(&mut self, from: usize) -> bool {
-        return self.0.unmap(Page::containing_address(VirtAddr::new(from as u64))).map(|f| f.1.flush()).is_ok();
+    unsafe fn unmap_memory(&mut self, from: usize) -> Option<usize> {
+        let mut batch = TlbBatch::new(self.2);
+        let frame = self.unmap_page(from, &mut batch);
+        batch.flush();
+        frame
+    }
+
+    /// Every page is invalidated on the other cores with a single shootdown
+    unsafe fn unmap_range(&mut self, range: Range<usize>) {
+        let mut batch = TlbBatch::new(self.2);
+        let mut page = range.start;
+        while page < range.end {
+            let size = self.page_size(page).unwrap_or(PageSize::Size4KiB);
+            self.unmap_page(page, &mut batch);
+            page = (page + 1).next_multiple_of(size.size());
+        }
+        batch.flush();
     }
 
     unsafe fn load_memory_map(&self) {
-        Cr3::write(self.1, Cr3Flags::empty());
+        tlb::load_page_table(self.1, self.2);
+    }
+
+    fn translate(&self, from: usize) -> Option<(usize, MemoryFlags)> {
+        let TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } = self.0.translate(VirtAddr::new(from as u64))
+        else {
+            return None;
+        };
+        let flags = match frame {
+            MappedFrame::Size4KiB(_) => flags.into(),
+            // Bit 7 of huge page entries is the huge page bit, not the PAT bit
+            _ => (flags - PageTableFlags::HUGE_PAGE).into(),
+        };
+        Some(((frame.start_address().as_u64() + offset) as usize, flags))
+    }
+
+    /// Huge pages overlapping `range` are changed as a whole, they can't be made write-combining
+    unsafe fn protect(&mut self, range: Range<usize>, flags: MemoryFlags) -> bool {
+        let mut batch = TlbBatch::new(self.2);
+        let mut address = range.start;
+        let mut updated = true;
+        while updated && address < range.end {
+            let TranslateResult::Mapped {
+                frame,
+                flags: old_flags,
+                ..
+            } = self.0.translate(VirtAddr::new(address as u64))
+            else {
+                updated = false;
+                break;
+            };
+            let new_flags = PageTableFlags::from(flags) | (old_flags - MEMORY_FLAGS_MASK);
+            updated = match frame {
+                MappedFrame::Size4KiB(_) if flags.contains(MemoryFlags::WRITE_COMBINING) => {
+                    self.update_flags_sized::<Size4KiB>(address, new_flags | PAT_4KIB, &mut batch)
+                }
+                MappedFrame::Size4KiB(_) => {
+                    self.update_flags_sized::<Size4KiB>(address, new_flags, &mut batch)
+                }
+                _ if flags.contains(MemoryFlags::WRITE_COMBINING) => false,
+                MappedFrame::Size2MiB(_) => {
+                    self.update_flags_sized::<Size2MiB>(address, new_flags, &mut batch)
+                }
+                MappedFrame::Size1GiB(_) => {
+                    self.update_flags_sized::<Size1GiB>(address, new_flags, &mut batch)
+                }
+            };
+            address = (address + 1).next_multiple_of(frame.size() as usize);
+        }
+        // Pages changed before a failure keep their new flags
+        batch.flush();
+        updated
+    }
+
+    unsafe fn remap_page(&mut self, from: usize, to: usize, flags: MemoryFlags) -> Option<usize> {
+        assert!(
+            from % PAGE_SIZE == 0 && to % PAGE_SIZE == 0,
+            "Pages must be aligned to their size"
+        );
+        let mut batch = TlbBatch::new(self.2);
+        let entry = self.level_1_entry(from)?;
+        let old_flags = entry.flags();
+        if !old_flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let old_frame = entry.addr().as_u64() as usize;
+        let mut new_flags = PageTableFlags::from(flags) | (old_flags - MEMORY_FLAGS_MASK);
+        if flags.contains(MemoryFlags::WRITE_COMBINING) {
+            new_flags |= PAT_4KIB;
+        }
+        // A single write, other cores walking the tables see either the old or the new entry
+        entry.set_addr(PhysAddr::new(to as u64), new_flags);
+        batch.push(from);
+        batch.flush();
+        Some(old_frame)
     }
 }
 
+/// Whether the CPU can map 1 GiB pages
+fn has_1gib_pages() -> bool {
+    CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .is_some_and(|features| features.has_1gib_pages())
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -110,13 +484,13 @@ mod tests {
 
     use crate::{
         bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+        kernel::{memory_map::physical_to_virtual, virtual_memory, KERNEL_MEMORY_MAP},
         limine::HHDM,
     };
 
     use super::*;
     use x86_64::structures::paging::{
         mapper::CleanUp, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
-        PageTableIndex,
     };
     #[test(name = "Map a page and then trying to access that memory through the virtual memory")]
     fn accessing_current_page_table_and_map_memory() {
@@ -133,16 +507,13 @@ mod tests {
             const TEST_VALUE: u64 = 0xe55fefabcdef;
             let mut allocator = GLOBAL_PAGE_ALLOCATOR.lock();
             let real_addr = allocator.allocate_frame().unwrap();
-            use PageTableIndex as PTI;
-            let page =
-                Page::from_page_table_indices(PTI::new(10), PTI::new(0), PTI::new(0), PTI::new(1));
+            let region = virtual_memory::reserve(PAGE_SIZE).unwrap();
+            let page = Page::containing_address(VirtAddr::new(region.start() as u64));
             mapper
                 .map_to(
                     page,
                     real_addr,
-                    PageTableFlags::WRITABLE
-                        | PageTableFlags::PRESENT
-                        | PageTableFlags::USER_ACCESSIBLE,
+                    PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
                     &mut *allocator,
                 )
                 .unwrap()
@@ -152,12 +523,183 @@ mod tests {
                 .write_volatile(TEST_VALUE);
             println!("Virtual address 0x{:X}", page.start_address().as_u64());
             assert_eq!(
-                (real_addr.start_address().as_u64() as *mut u64).read_volatile(),
+                (physical_to_virtual(real_addr.start_address().as_u64() as usize) as *mut u64)
+                    .read_volatile(),
                 TEST_VALUE
             );
             mapper.unmap(page).unwrap().1.flush();
             allocator.free_pages(real_addr.start_address().as_u64() as usize, PAGE_SIZE);
             mapper.clean_up_addr_range(Page::range_inclusive(page, page), allocator.deref_mut());
+            virtual_memory::release(region);
+        }
+    }
+
+    #[test(name = "Translate, protect and unmap a range of pages")]
+    fn translate_and_protect_range() {
+        const PAGES: usize = 4;
+        let frames = GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .request_pages(PAGES, PAGE_SIZE, usize::MAX)
+            .unwrap();
+        let region = virtual_memory::reserve(PAGES * PAGE_SIZE).unwrap();
+        let range = region.start()..region.end();
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        unsafe {
+            assert!(mapper.map_range(
+                range.clone(),
+                frames.get(),
+                MemoryFlags::WRITABLE,
+                PageSize::Size4KiB
+            ));
+            for page in 0..PAGES {
+                let address = region.start() + page * PAGE_SIZE + 0x123;
+                assert_eq!(
+                    mapper.translate(address),
+                    Some((
+                        frames.get() + page * PAGE_SIZE + 0x123,
+                        MemoryFlags::WRITABLE
+                    ))
+                );
+            }
+            // Mapping over pages that are already mapped fails without touching them
+            assert!(!mapper.map_range(
+                range.clone(),
+                frames.get(),
+                MemoryFlags::WRITABLE,
+                PageSize::Size4KiB
+            ));
+            assert!(mapper.translate(region.start()).is_some());
+
+            let read_only = MemoryFlags::NO_EXECUTE | MemoryFlags::WRITE_COMBINING;
+            assert!(mapper.protect(range.clone(), read_only));
+            assert_eq!(mapper.translate(region.end() - 1).unwrap().1, read_only);
+            assert!(mapper.protect(range.clone(), MemoryFlags::WRITABLE));
+            assert_eq!(
+                mapper.translate(region.start()).unwrap().1,
+                MemoryFlags::WRITABLE
+            );
+
+            mapper.unmap_range(range.clone());
+            assert!(range
+                .step_by(PAGE_SIZE)
+                .all(|page| mapper.translate(page).is_none()));
+            assert!(!mapper.protect(region.start()..region.end(), MemoryFlags::WRITABLE));
+        }
+        drop(mapper);
+        virtual_memory::release(region);
+        GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .free_contiguous_pages(frames, PAGES);
+    }
+
+    #[test(name = "Remap a page to another frame without unmapping it")]
+    fn remap_page() {
+        let frames = GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .request_pages(2, PAGE_SIZE, usize::MAX)
+            .unwrap()
+            .get();
+        let region = virtual_memory::reserve(PAGE_SIZE).unwrap();
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        unsafe {
+            assert_eq!(
+                mapper.remap_page(region.start(), frames, MemoryFlags::WRITABLE),
+                None
+            );
+            assert!(mapper.map_memory(region.start(), frames, MemoryFlags::empty()));
+            assert_eq!(
+                mapper.remap_page(region.start(), frames + PAGE_SIZE, MemoryFlags::WRITABLE),
+                Some(frames)
+            );
+            assert_eq!(
+                mapper.translate(region.start()),
+                Some((frames + PAGE_SIZE, MemoryFlags::WRITABLE))
+            );
+            (region.start() as *mut u64).write_volatile(0x1234);
+            assert_eq!(
+                (physical_to_virtual(frames + PAGE_SIZE) as *const u64).read_volatile(),
+                0x1234
+            );
+            assert_eq!(
+                mapper.unmap_memory(region.start()),
+                Some(frames + PAGE_SIZE)
+            );
+        }
+        drop(mapper);
+        virtual_memory::release(region);
+        GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .free_pages(frames, 2 * PAGE_SIZE);
+    }
+
+    #[test(name = "Map a range with huge pages where it is aligned")]
+    fn map_huge_pages() {
+        const HUGE_PAGE: usize = 512 * PAGE_SIZE;
+        let frames = GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .request_pages(1024, HUGE_PAGE, usize::MAX)
+            .unwrap()
+            .get();
+        let region = virtual_memory::reserve_aligned(3 * HUGE_PAGE, HUGE_PAGE).unwrap();
+        // Starts a page before a huge page boundary, so it needs one small page first
+        let start = region.start() + HUGE_PAGE - PAGE_SIZE;
+        let physical_start = frames + HUGE_PAGE - PAGE_SIZE;
+        let range = start..start + HUGE_PAGE + PAGE_SIZE;
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        unsafe {
+            assert!(mapper.map_range(
+                range.clone(),
+                physical_start,
+                MemoryFlags::WRITABLE,
+                PageSize::Size1GiB
+            ));
+            assert_eq!(mapper.page_size(start), Some(PageSize::Size4KiB));
+            assert_eq!(
+                mapper.page_size(start + PAGE_SIZE),
+                Some(PageSize::Size2MiB)
+            );
+            assert_eq!(
+                mapper.translate(start + PAGE_SIZE + 0x1234),
+                Some((frames + HUGE_PAGE + 0x1234, MemoryFlags::WRITABLE))
+            );
+            ((start + PAGE_SIZE + 0x1234) as *mut u64).write_volatile(42);
+            assert_eq!(
+                (physical_to_virtual(frames + HUGE_PAGE + 0x1234) as *const u64).read_volatile(),
+                42
+            );
+
+            assert!(mapper.protect(range.clone(), MemoryFlags::NO_EXECUTE));
+            assert_eq!(
+                mapper.translate(range.end - 1).unwrap().1,
+                MemoryFlags::NO_EXECUTE
+            );
+            // Huge pages can't use the PAT entry for write-combining
+            assert!(!mapper.protect(range.clone(), MemoryFlags::WRITE_COMBINING));
+            mapper.unmap_range(range.clone());
+            assert_eq!(mapper.page_size(start + PAGE_SIZE), None);
+
+            // So they are split into small pages when mapping write-combining memory
+            assert!(mapper.map_page(
+                region.start(),
+                frames,
+                PageSize::Size2MiB,
+                MemoryFlags::WRITE_COMBINING
+            ));
+            assert_eq!(
+                mapper.page_size(region.start() + PAGE_SIZE),
+                Some(PageSize::Size4KiB)
+            );
+            assert_eq!(
+                mapper.translate(region.start()).unwrap().1,
+                MemoryFlags::WRITE_COMBINING
+            );
+            mapper.unmap_range(region.start()..region.end());
+            assert_eq!(mapper.translate(region.start()), None);
         }
+        drop(mapper);
+        virtual_memory::release(region);
+        GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .free_pages(frames, 2 * HUGE_PAGE);
     }
 }
@@ -1,8 +1,43 @@
-const MAIN_PIC_DATA_PORT: u16 = 0x00A0;
+use super::ports::{read, write};
+
+const MAIN_PIC_COMMAND_PORT: u16 = 0x0020;
+const MAIN_PIC_DATA_PORT: u16 = 0x0021;
+const SECONDARY_PIC_COMMAND_PORT: u16 = 0x00A0;
 const SECONDARY_PIC_DATA_PORT: u16 = 0x00A1;
+/// Unused port, writing to it gives the PICs time to take the previous write
+const WAIT_PORT: u16 = 0x0080;
+/// Vectors the PICs use after [`disable`], only spurious interrupts can still arrive there
+pub const PIC_VECTOR_BASE: u8 = 0xF0;
+/// Spurious interrupts of the PICs come in as their lowest priority IRQ, 7 and 15
+pub const PIC_SPURIOUS_VECTORS: [u8; 2] = [PIC_VECTOR_BASE + 7, PIC_VECTOR_BASE + 15];
+
+/// Moves the PICs away from the exception vectors and masks every IRQ, the I/O APIC replaces them
 pub fn disable() {
     unsafe {
-        super::ports::write(MAIN_PIC_DATA_PORT, 0xFF);
-        super::ports::write(SECONDARY_PIC_DATA_PORT, 0xFF);
+        let wait = || write(WAIT_PORT, 0);
+        // Initialization, the PICs expect 3 more words on their data ports
+        for (port, word) in [
+            (MAIN_PIC_COMMAND_PORT, 0x11),
+            (SECONDARY_PIC_COMMAND_PORT, 0x11),
+            // Vector offsets
+            (MAIN_PIC_DATA_PORT, PIC_VECTOR_BASE),
+            (SECONDARY_PIC_DATA_PORT, PIC_VECTOR_BASE + 8),
+            // The secondary PIC is connected to IRQ 2 of the main one
+            (MAIN_PIC_DATA_PORT, 1 << 2),
+            (SECONDARY_PIC_DATA_PORT, 2),
+            // 8086 mode
+            (MAIN_PIC_DATA_PORT, 0x01),
+            (SECONDARY_PIC_DATA_PORT, 0x01),
+            (MAIN_PIC_DATA_PORT, 0xFF),
+            (SECONDARY_PIC_DATA_PORT, 0xFF),
+        ] {
+            write(port, word);
+            wait();
+        }
     }
 }
+
+/// Whether every IRQ of both PICs is masked
+pub fn masked() -> bool {
+    unsafe { read(MAIN_PIC_DATA_PORT) == 0xFF && read(SECONDARY_PIC_DATA_PORT) == 0xFF }
+}
@@ -0,0 +1,49 @@
+use super::ports::{read, write};
+
+/// Frequency the counters of the PIT count down at
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// ISA IRQ of channel 0
+pub const PIT_IRQ: u8 = 0;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+/// Bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5 is its output
+const CHANNEL_2_CONTROL_PORT: u16 = 0x61;
+
+/// Channel 0 as a rate generator, low then high byte of the divisor
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Channel 2 interrupting on terminal count, low then high byte of the count
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Makes channel 0 fire IRQ 0 `frequency` times per second
+pub fn start_periodic(frequency: u64) {
+    let divisor = (PIT_FREQUENCY / frequency).clamp(1, u16::MAX as u64) as u16;
+    unsafe {
+        write(COMMAND_PORT, CHANNEL_0_RATE_GENERATOR);
+        write(CHANNEL_0_PORT, divisor as u8);
+        write(CHANNEL_0_PORT, (divisor >> 8) as u8);
+    }
+}
+
+/// Spins for `microseconds` using channel 2, which works without interrupts
+///
+/// At most 54924 microseconds can be waited at once.
+pub fn wait(microseconds: u64) {
+    let count = u16::try_from(microseconds * PIT_FREQUENCY / 1_000_000)
+        .expect("The PIT can't wait that long at once");
+    unsafe {
+        // Gate off and speaker off while the count is set
+        let control = read(CHANNEL_2_CONTROL_PORT) & !0b11;
+        write(CHANNEL_2_CONTROL_PORT, control);
+        write(COMMAND_PORT, CHANNEL_2_ONE_SHOT);
+        write(CHANNEL_2_PORT, count as u8);
+        write(CHANNEL_2_PORT, (count >> 8) as u8);
+        // Counting starts when the gate goes up
+        write(CHANNEL_2_CONTROL_PORT, control | 1);
+        while read(CHANNEL_2_CONTROL_PORT) & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        write(CHANNEL_2_CONTROL_PORT, control);
+    }
+}
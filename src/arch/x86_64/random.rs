@@ -0,0 +1,59 @@
+use core::{
+    arch::{asm, x86_64::_rdtsc},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use x86_64::instructions::random::RdRand;
+
+/// How many times RDSEED and RDRAND are retried when they run out of entropy
+const RETRIES: usize = 10;
+
+/// Mixed into the timestamp counter, so numbers taken at almost the same time still differ
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn rdseed() -> Option<u64> {
+    let value: u64;
+    let success: u8;
+    unsafe {
+        asm!(
+            "rdseed {value}",
+            "setc {success}",
+            value = out(reg) value,
+            success = out(reg_byte) success,
+            options(nomem, nostack)
+        )
+    };
+    (success != 0).then_some(value)
+}
+
+/// Random number from RDSEED, or RDRAND if the CPU has no RDSEED or it has nothing left
+///
+/// Returns None if the CPU has neither or both keep failing.
+pub fn hardware_random() -> Option<u64> {
+    let has_rdseed = CpuId::new()
+        .get_extended_feature_info()
+        .is_some_and(|features| features.has_rdseed());
+    if has_rdseed {
+        if let Some(value) = (0..RETRIES).find_map(|_| rdseed()) {
+            return Some(value);
+        }
+    }
+    let rdrand = RdRand::new()?;
+    (0..RETRIES).find_map(|_| rdrand.get_u64())
+}
+
+/// Random number for placing things in memory, falls back to the timestamp counter without hardware entropy
+///
+/// Not suited for cryptography.
+pub fn random_u64() -> u64 {
+    if let Some(value) = hardware_random() {
+        return value;
+    }
+    // SplitMix64 over the timestamp counter
+    let mut value =
+        FALLBACK_STATE.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed) ^ unsafe { _rdtsc() };
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
@@ -0,0 +1,208 @@
+use core::{
+    arch::{asm, x86_64::__rdtscp},
+    hint::spin_loop,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use limine::smp::Cpu;
+use raw_cpuid::CpuId;
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::{interrupts, tlb::flush_all},
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::Msr,
+    },
+};
+
+use super::{
+    apic::LAPIC,
+    gdt,
+    idt::{self, WAKE_UP_INTERRUPT_ID},
+    paging, tlb, user_access,
+};
+use crate::{
+    kernel::{memory_map::MemoryMap, KERNEL_MEMORY_MAP},
+    limine::SMP,
+    multicore::current_core_id,
+};
+
+/// Highest number of cores the kernel can use, LAPIC IDs must be below it
+pub const MAX_CORES: usize = 64;
+const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+/// Instruction that reads IA32_TSC_AUX back, where [`bring_online`] caches the ID of each core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TscAuxRead {
+    Rdpid,
+    Rdtscp,
+    Unsupported,
+}
+static TSC_AUX_READ: Once<TscAuxRead> = Once::new();
+
+/// Cores that finished starting up, by LAPIC ID
+static ONLINE_CORES: AtomicU64 = AtomicU64::new(0);
+
+/// Function each core was asked to run by [`run_on_core`], 0 when there is none
+static CALLS: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(0) }; MAX_CORES];
+/// What the last function each core ran returned
+static RESULTS: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(0) }; MAX_CORES];
+/// Held while waiting for a core to run a function, so only one is sent at a time
+static CALLERS: [Mutex<()>; MAX_CORES] = [const { Mutex::new(()) }; MAX_CORES];
+
+/// Cores that finished starting up
+pub fn online_cores() -> impl Iterator<Item = usize> {
+    let online = ONLINE_CORES.load(Ordering::Acquire);
+    (0..MAX_CORES).filter(move |core| online & (1 << core) != 0)
+}
+
+/// Whether more than one core is online, otherwise nothing has to be sent to the others
+pub fn other_cores_online() -> bool {
+    ONLINE_CORES.load(Ordering::Acquire).count_ones() > 1
+}
+
+fn tsc_aux_read() -> TscAuxRead {
+    *TSC_AUX_READ.call_once(|| {
+        let cpuid = CpuId::new();
+        if cpuid
+            .get_extended_feature_info()
+            .is_some_and(|features| features.has_rdpid())
+        {
+            TscAuxRead::Rdpid
+        } else if cpuid
+            .get_extended_processor_and_feature_identifiers()
+            .is_some_and(|features| features.has_rdtscp())
+        {
+            TscAuxRead::Rdtscp
+        } else {
+            TscAuxRead::Unsupported
+        }
+    })
+}
+
+/// LAPIC ID of the current core cached by [`bring_online`], None before it or if the CPU can't read it back
+pub fn cached_core_id() -> Option<usize> {
+    let aux = match tsc_aux_read() {
+        TscAuxRead::Rdpid => {
+            let aux: u64;
+            unsafe { asm!("rdpid {}", out(reg) aux, options(nomem, nostack, preserves_flags)) };
+            aux as u32
+        }
+        TscAuxRead::Rdtscp => {
+            let mut aux = 0;
+            unsafe { __rdtscp(&mut aux) };
+            aux
+        }
+        TscAuxRead::Unsupported => return None,
+    };
+    // The ID is stored plus 1, so the 0 it has after a reset means it isn't cached yet
+    (aux as usize).checked_sub(1)
+}
+
+/// Enables the LAPIC of the current core with its timer stopped and marks the core as online
+///
+/// # Safety
+/// The IDT must be loaded
+unsafe fn bring_online() {
+    let mut lapic = LAPIC.write();
+    lapic.enable();
+    lapic.disable_timer();
+    drop(lapic);
+    let core = current_core_id();
+    assert!(core < MAX_CORES, "LAPIC ID {core} is too big");
+    if tsc_aux_read() != TscAuxRead::Unsupported {
+        Msr::new(IA32_TSC_AUX).write(core as u64 + 1);
+    }
+    tlb::init();
+    ONLINE_CORES.fetch_or(1 << core, Ordering::Release);
+    // Shootdowns sent before this core was online didn't reach it
+    flush_all();
+}
+
+/// Starts every other core, they wait for [`run_on_core`] afterwards, then enables interrupts on this one
+///
+/// # Safety
+/// Must be called once on the bootstrap core after the kernel image was remapped
+pub unsafe fn start_application_processors() {
+    let smp = SMP
+        .get_response()
+        .expect("The bootloader didn't start the other cores");
+    idt::register_system_handlers();
+    bring_online();
+    for cpu in smp.cpus() {
+        if cpu.lapic_id != smp.bsp_lapic_id() {
+            cpu.goto_address.write(application_processor_entry);
+        }
+    }
+    while ONLINE_CORES.load(Ordering::Acquire).count_ones() as usize != smp.cpus().len() {
+        spin_loop();
+    }
+    interrupts::enable();
+}
+
+/// Where the other cores start, on the stack the bootloader gave them
+unsafe extern "C" fn application_processor_entry(_cpu: &Cpu) -> ! {
+    // The kernel memory map shares everything with the bootloader's besides the kernel image
+    KERNEL_MEMORY_MAP.lock().load_memory_map();
+    idt::init();
+    paging::init_pat();
+    user_access::enable_user_memory_protection();
+    Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+    gdt::init();
+    gdt::switch_to_kernel_stack(application_processor_main)
+}
+
+/// Waits for [`run_on_core`] on a guarded kernel stack
+extern "C" fn application_processor_main() -> ! {
+    unsafe { bring_online() };
+    let core = current_core_id();
+    loop {
+        // A wake up sent between the check and the halt is taken right after `sti`, which ends the halt
+        interrupts::disable();
+        let call = CALLS[core].load(Ordering::Acquire);
+        if call == 0 {
+            interrupts::enable_and_hlt();
+            continue;
+        }
+        interrupts::enable();
+        let function: fn() -> usize = unsafe { core::mem::transmute(call) };
+        RESULTS[core].store(function(), Ordering::Relaxed);
+        CALLS[core].store(0, Ordering::Release);
+    }
+}
+
+/// Runs `function` on another online core and waits for what it returns
+pub fn run_on_core(core: usize, function: fn() -> usize) -> usize {
+    assert!(
+        core < MAX_CORES && ONLINE_CORES.load(Ordering::Acquire) & (1 << core) != 0,
+        "Core {core} isn't online"
+    );
+    assert_ne!(core, current_core_id(), "Can't wait for the current core");
+    let _caller = CALLERS[core].lock();
+    CALLS[core].store(function as usize, Ordering::Release);
+    interrupts::without_interrupts(|| unsafe {
+        LAPIC.write().send_ipi(WAKE_UP_INTERRUPT_ID, core as u32)
+    });
+    while CALLS[core].load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+    RESULTS[core].load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached_id_matches_lapic() -> usize {
+        let lapic_id = interrupts::without_interrupts(|| unsafe { LAPIC.read().id() });
+        cached_core_id().map_or(1, |core| (core == lapic_id as usize) as usize)
+    }
+
+    #[test(name = "Every online core caches its own LAPIC ID")]
+    fn test_cached_core_id() {
+        assert_eq!(cached_id_matches_lapic(), 1);
+        for core in online_cores().filter(|&core| core != current_core_id()) {
+            assert_eq!(run_on_core(core, cached_id_matches_lapic), 1);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use spin::Once;
+use x2apic::lapic::{TimerDivide, TimerMode};
+use x86_64::{instructions::interrupts, registers::model_specific::Msr};
+
+use super::{apic::LAPIC, pit};
+use crate::{
+    kernel::clock::{self, NANOSECONDS_PER_SECOND},
+    multicore::current_core_id,
+};
+
+/// Divider every core uses for its LAPIC timer
+pub const TIMER_DIVIDE: TimerDivide = TimerDivide::Div16;
+/// How long the LAPIC timer and the TSC are measured against the PIT
+const CALIBRATION_MICROSECONDS: u64 = 10_000;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// How fast the LAPIC timer and the TSC count, measured once by [`calibrate`]
+#[derive(Debug, Clone, Copy)]
+pub struct Calibration {
+    /// Counts of the LAPIC timer per second with [`TIMER_DIVIDE`]
+    pub lapic_frequency: u64,
+    pub tsc_frequency: u64,
+    /// TSC when the calibration ended, where [`nanoseconds`] starts
+    tsc_start: u64,
+    /// Whether the TSC runs at the same rate in every power state, otherwise [`nanoseconds`] uses the ticks
+    invariant_tsc: bool,
+}
+
+static CALIBRATION: Once<Calibration> = Once::new();
+/// Core whose periodic timer drives the ticks of the clock, `usize::MAX` when there is none
+static TICK_CORE: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Highest value [`nanoseconds`] returned, so it never goes back when the TSCs of the cores differ slightly
+static LAST_NANOSECONDS: AtomicU64 = AtomicU64::new(0);
+
+fn calibration() -> &'static Calibration {
+    CALIBRATION.get().expect("The LAPIC timer isn't calibrated")
+}
+
+/// Converts nanoseconds into counts of something running at `frequency`, rounding up
+fn counts(nanoseconds: u64, frequency: u64) -> u64 {
+    (nanoseconds as u128 * frequency as u128).div_ceil(NANOSECONDS_PER_SECOND as u128) as u64
+}
+
+/// Measures the LAPIC timer and the TSC against the PIT, the LAPIC of the current core must be enabled
+///
+/// # Safety
+/// The PIT channel 2 must not be used by anything else meanwhile
+pub unsafe fn calibrate() -> &'static Calibration {
+    CALIBRATION.call_once(|| {
+        interrupts::without_interrupts(|| {
+            let mut lapic = LAPIC.write();
+            lapic.disable_timer();
+            lapic.set_timer_mode(TimerMode::OneShot);
+            lapic.set_timer_divide(TIMER_DIVIDE);
+            lapic.set_timer_initial(u32::MAX);
+            let tsc_start = _rdtsc();
+            pit::wait(CALIBRATION_MICROSECONDS);
+            let lapic_counts = u32::MAX - lapic.timer_current();
+            let tsc_end = _rdtsc();
+            lapic.set_timer_initial(0);
+            Calibration {
+                lapic_frequency: lapic_counts as u64 * 1_000_000 / CALIBRATION_MICROSECONDS,
+                tsc_frequency: (tsc_end - tsc_start) * 1_000_000 / CALIBRATION_MICROSECONDS,
+                tsc_start: tsc_end,
+                invariant_tsc: CpuId::new()
+                    .get_advanced_power_mgmt_info()
+                    .is_some_and(|info| info.has_invariant_tsc()),
+            }
+        })
+    })
+}
+
+/// Nanoseconds since [`calibrate`] measured with the TSC, 0 before it
+///
+/// Without an invariant TSC only the ticks are counted, and the time stands still while the clock doesn't tick.
+pub fn nanoseconds() -> u64 {
+    let Some(calibration) = CALIBRATION.get() else {
+        return 0;
+    };
+    let (elapsed, frequency) = if calibration.invariant_tsc {
+        // Another core may have a TSC slightly behind the one of the calibrating core
+        let elapsed = unsafe { _rdtsc() }.saturating_sub(calibration.tsc_start);
+        (elapsed, calibration.tsc_frequency)
+    } else {
+        (clock::ticks(), clock::tick_frequency())
+    };
+    if frequency == 0 {
+        return LAST_NANOSECONDS.load(Ordering::Relaxed);
+    }
+    let nanoseconds = (elapsed as u128 * NANOSECONDS_PER_SECOND as u128 / frequency as u128) as u64;
+    LAST_NANOSECONDS
+        .fetch_max(nanoseconds, Ordering::Relaxed)
+        .max(nanoseconds)
+}
+
+/// Makes the LAPIC timer of the current core fire `frequency` times per second, every tick advances the clock
+///
+/// Only one core drives the clock, starting the timer on another one moves it there.
+pub fn start_periodic(frequency: u64) {
+    let initial = u32::try_from(calibration().lapic_frequency / frequency)
+        .expect("The tick frequency is too low for the LAPIC timer");
+    assert!(
+        initial > 0,
+        "The tick frequency is too high for the LAPIC timer"
+    );
+    interrupts::without_interrupts(|| {
+        TICK_CORE.store(current_core_id(), Ordering::Relaxed);
+        clock::set_tick_frequency(frequency);
+        let mut lapic = LAPIC.write();
+        unsafe {
+            lapic.set_timer_mode(TimerMode::Periodic);
+            lapic.set_timer_divide(TIMER_DIVIDE);
+            lapic.set_timer_initial(initial);
+            lapic.enable_timer();
+        }
+    });
+}
+
+/// Makes the LAPIC timer of the current core fire once in `nanoseconds`, for tickless operation
+///
+/// Handlers registered for the timer vector run when it fires.
+pub fn set_one_shot(nanoseconds: u64) {
+    let initial = counts(nanoseconds, calibration().lapic_frequency).clamp(1, u32::MAX as u64);
+    interrupts::without_interrupts(|| {
+        stop_ticking();
+        let mut lapic = LAPIC.write();
+        unsafe {
+            lapic.set_timer_mode(TimerMode::OneShot);
+            lapic.set_timer_divide(TIMER_DIVIDE);
+            lapic.set_timer_initial(initial as u32);
+            lapic.enable_timer();
+        }
+    });
+}
+
+/// Whether the LAPIC timer can fire when the TSC reaches a deadline
+pub fn tsc_deadline_supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .is_some_and(|features| features.has_tsc_deadline())
+}
+
+/// Like [`set_one_shot`] with the TSC deadline mode, which doesn't drift, returns false if the CPU doesn't have it
+pub fn set_tsc_deadline(nanoseconds: u64) -> bool {
+    if !tsc_deadline_supported() {
+        return false;
+    }
+    let deadline = counts(nanoseconds, calibration().tsc_frequency);
+    interrupts::without_interrupts(|| {
+        stop_ticking();
+        let mut lapic = LAPIC.write();
+        unsafe {
+            lapic.set_timer_mode(TimerMode::TscDeadline);
+            lapic.enable_timer();
+            // The mode has to be set before the deadline, otherwise the write is ignored
+            Msr::new(IA32_TSC_DEADLINE).write(_rdtsc() + deadline.max(1));
+        }
+    });
+    true
+}
+
+/// Stops the LAPIC timer of the current core
+pub fn stop() {
+    interrupts::without_interrupts(|| {
+        stop_ticking();
+        let mut lapic = LAPIC.write();
+        unsafe {
+            lapic.disable_timer();
+            lapic.set_timer_initial(0);
+        }
+    });
+}
+
+/// The clock stops ticking if the current core was driving it
+fn stop_ticking() {
+    let core = current_core_id();
+    if TICK_CORE
+        .compare_exchange(core, usize::MAX, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        clock::set_tick_frequency(0);
+    }
+}
+
+/// Handler of the timer vector
+pub fn on_timer_interrupt() {
+    if TICK_CORE.load(Ordering::Relaxed) == current_core_id() {
+        clock::tick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{hint::spin_loop, sync::atomic::AtomicUsize};
+
+    use super::*;
+    use crate::{
+        arch::x86_64::{idt::APIC_TIMER_INTERRUPT_ID, smp},
+        kernel::interrupts,
+    };
+
+    #[test(name = "The clock ticks at the calibrated frequency")]
+    fn test_clock() {
+        let calibration = calibration();
+        assert!(calibration.lapic_frequency > 0);
+        assert!(calibration.tsc_frequency > 0);
+        assert_eq!(clock::tick_frequency(), clock::DEFAULT_TICK_FREQUENCY);
+        let ticks = clock::ticks();
+        let start = clock::nanoseconds();
+        pit::wait(20_000);
+        let elapsed = clock::nanoseconds() - start;
+        // Emulators are slow, only the order of magnitude is checked
+        assert!((10_000_000..1_000_000_000).contains(&elapsed));
+        assert!(clock::ticks() > ticks);
+    }
+
+    static FIRED_CORE: AtomicUsize = AtomicUsize::new(usize::MAX);
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Fires the timer of the current core once in each one-shot mode, returns how often it fired
+    fn fire_once() -> usize {
+        FIRED_CORE.store(current_core_id(), Ordering::Relaxed);
+        set_one_shot(1_000_000);
+        while FIRED.load(Ordering::Relaxed) < 1 {
+            spin_loop();
+        }
+        if set_tsc_deadline(1_000_000) {
+            while FIRED.load(Ordering::Relaxed) < 2 {
+                spin_loop();
+            }
+        } else {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        }
+        stop();
+        FIRED_CORE.store(usize::MAX, Ordering::Relaxed);
+        FIRED.load(Ordering::Relaxed)
+    }
+
+    #[test(name = "One-shot timers fire once on the core that set them")]
+    fn test_one_shot() {
+        let Some(core) = smp::online_cores().find(|&core| core != current_core_id()) else {
+            println!("Only one core is online, skipping");
+            return;
+        };
+        let handler = interrupts::register(APIC_TIMER_INTERRUPT_ID.into(), || {
+            if FIRED_CORE.load(Ordering::Relaxed) == current_core_id() {
+                FIRED.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        assert_eq!(smp::run_on_core(core, fire_once), 2);
+        assert!(interrupts::unregister(handler));
+    }
+}
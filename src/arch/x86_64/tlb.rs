@@ -0,0 +1,383 @@
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::{
+    instructions::{
+        interrupts,
+        tlb::{self, InvPicdCommand, Pcid},
+    },
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::PhysFrame,
+    VirtAddr,
+};
+
+use super::{
+    apic::LAPIC,
+    idt::TLB_SHOOTDOWN_INTERRUPT_ID,
+    smp::{self, MAX_CORES},
+    user_access::USER_SPACE_END,
+};
+use crate::multicore::current_core_id;
+
+/// Pages a shootdown invalidates one by one, the TLB is flushed as a whole for more than this
+const MAX_BATCH_PAGES: usize = 32;
+/// PCIDs available on x86_64
+const PCID_COUNT: usize = 4096;
+/// Memory maps with this PCID are flushed every time they are loaded, the kernel memory map uses it
+pub const SHARED_PCID: u16 = 0;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// PCIDs handed out by [`allocate_pcid`], a set bit is in use
+static USED_PCIDS: Mutex<PcidSet> = Mutex::new(PcidSet::with_shared());
+
+/// Invalidations other cores asked each core for
+static QUEUES: [Mutex<InvalidationQueue>; MAX_CORES] =
+    [const { Mutex::new(InvalidationQueue::new()) }; MAX_CORES];
+/// Number of shootdowns each core has completed, compared against [`InvalidationQueue::requested`]
+static COMPLETED: [AtomicU64; MAX_CORES] = [const { AtomicU64::new(0) }; MAX_CORES];
+
+/// Enables PCIDs if the CPU has them, every core must call this after loading the kernel memory map
+///
+/// # Safety
+/// The loaded CR3 must have its PCID and flags cleared
+pub unsafe fn init() {
+    let cpuid = CpuId::new();
+    if !cpuid
+        .get_feature_info()
+        .is_some_and(|features| features.has_pcid())
+    {
+        return;
+    }
+    Cr4::update(|flags| *flags |= Cr4Flags::PCID);
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    if cpuid
+        .get_extended_feature_info()
+        .is_some_and(|features| features.has_invpcid())
+    {
+        INVPCID_SUPPORTED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Gives a memory map its own PCID, so switching to it keeps the translations of the others cached
+///
+/// Returns [`SHARED_PCID`] if PCIDs aren't enabled or all of them are in use.
+pub fn allocate_pcid() -> u16 {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return SHARED_PCID;
+    }
+    USED_PCIDS.lock().take_free().unwrap_or(SHARED_PCID)
+}
+
+/// Returns a PCID from [`allocate_pcid`], its translations are flushed on every core before it is used again
+///
+/// # Safety
+/// No core may have the memory map using it loaded
+pub unsafe fn release_pcid(pcid: u16) {
+    if pcid == SHARED_PCID {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        for core in smp::online_cores() {
+            QUEUES[core].lock().stale_pcids.insert(pcid);
+        }
+    });
+    USED_PCIDS.lock().remove(pcid);
+}
+
+/// Loads the level 4 table in `frame` tagged with `pcid`, its cached translations are only
+/// flushed if one of them may be stale
+///
+/// # Safety
+/// Same as writing CR3
+pub unsafe fn load_page_table(frame: PhysFrame, pcid: u16) {
+    if !PCID_ENABLED.load(Ordering::Relaxed) || pcid == SHARED_PCID {
+        Cr3::write(frame, Cr3Flags::empty());
+        return;
+    }
+    let tag = Pcid::new(pcid).unwrap();
+    interrupts::without_interrupts(|| {
+        if QUEUES[current_core_id()].lock().stale_pcids.remove(pcid) {
+            Cr3::write_pcid(frame, tag);
+        } else {
+            Cr3::write_pcid_no_flush(frame, tag);
+        }
+    });
+}
+
+/// Set of PCIDs
+struct PcidSet([u64; PCID_COUNT / 64]);
+impl PcidSet {
+    const fn new() -> Self {
+        Self([0; PCID_COUNT / 64])
+    }
+    const fn with_shared() -> Self {
+        let mut set = Self::new();
+        set.0[0] = 1 << SHARED_PCID;
+        set
+    }
+    fn insert(&mut self, pcid: u16) {
+        self.0[pcid as usize / 64] |= 1 << (pcid % 64);
+    }
+    fn insert_all(&mut self) {
+        self.0 = [u64::MAX; PCID_COUNT / 64];
+    }
+    /// Returns whether `pcid` was in the set
+    fn remove(&mut self, pcid: u16) -> bool {
+        let word = &mut self.0[pcid as usize / 64];
+        let bit = 1 << (pcid % 64);
+        let contained = *word & bit != 0;
+        *word &= !bit;
+        contained
+    }
+    /// Inserts the first PCID that isn't in the set yet
+    fn take_free(&mut self) -> Option<u16> {
+        let (index, word) = self
+            .0
+            .iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != u64::MAX)?;
+        let bit = word.trailing_ones();
+        *word |= 1 << bit;
+        Some((index * 64) as u16 + bit as u16)
+    }
+}
+
+/// Invalidations a core still has to do, and the PCIDs it has to flush before loading them
+struct InvalidationQueue {
+    pages: [(usize, u16); MAX_BATCH_PAGES],
+    len: usize,
+    /// Too many pages were queued, everything has to be flushed
+    flush_all: bool,
+    /// Shootdowns queued so far
+    requested: u64,
+    /// PCIDs other than the loaded one that may have stale translations on this core
+    stale_pcids: PcidSet,
+}
+impl InvalidationQueue {
+    const fn new() -> Self {
+        Self {
+            pages: [(0, 0); MAX_BATCH_PAGES],
+            len: 0,
+            flush_all: false,
+            requested: 0,
+            stale_pcids: PcidSet::new(),
+        }
+    }
+    fn push(&mut self, batch: &TlbBatch) {
+        if batch.flush_all || self.len + batch.len > MAX_BATCH_PAGES {
+            self.flush_all = true;
+        } else {
+            for &address in &batch.pages[..batch.len] {
+                self.pages[self.len] = (address, batch.pcid);
+                self.len += 1;
+            }
+        }
+        self.requested += 1;
+    }
+    /// Invalidates the translation of `address` tagged with `pcid` on this core
+    fn invalidate(&mut self, address: usize, pcid: u16) {
+        let address = VirtAddr::new(address as u64);
+        if !PCID_ENABLED.load(Ordering::Relaxed) {
+            tlb::flush(address);
+            return;
+        }
+        let loaded_pcid = Cr3::read_pcid().1.value();
+        if address.as_u64() as usize >= USER_SPACE_END {
+            // Kernel pages can be cached with any PCID
+            tlb::flush(address);
+            self.stale_pcids.insert_all();
+        } else if pcid == loaded_pcid {
+            tlb::flush(address);
+        } else if pcid == SHARED_PCID {
+            // Flushed anyway when it is loaded again
+        } else if INVPCID_SUPPORTED.load(Ordering::Relaxed) {
+            unsafe { tlb::flush_pcid(InvPicdCommand::Address(address, Pcid::new(pcid).unwrap())) };
+        } else {
+            self.stale_pcids.insert(pcid);
+        }
+    }
+    fn invalidate_everything(&mut self) {
+        if !PCID_ENABLED.load(Ordering::Relaxed) {
+            tlb::flush_all();
+            return;
+        }
+        self.stale_pcids.insert_all();
+        // Reloading without NOFLUSH flushes the loaded PCID
+        let (frame, pcid) = Cr3::read_pcid();
+        unsafe { Cr3::write_pcid(frame, pcid) };
+    }
+    /// Does every queued invalidation, interrupts must be disabled
+    fn process(&mut self, core: usize) {
+        if self.flush_all {
+            self.invalidate_everything();
+        } else {
+            for index in 0..self.len {
+                let (address, pcid) = self.pages[index];
+                self.invalidate(address, pcid);
+            }
+        }
+        self.len = 0;
+        self.flush_all = false;
+        COMPLETED[core].store(self.requested, Ordering::Release);
+    }
+}
+
+/// Does the invalidations other cores queued for this one, called by the shootdown interrupt handler
+pub fn process_shootdowns() {
+    interrupts::without_interrupts(|| {
+        let core = current_core_id();
+        QUEUES[core].lock().process(core);
+    });
+}
+
+/// Pages of one memory map whose cached translations have to be invalidated on every core
+///
+/// Changes to page tables are collected here and sent to the other cores in a single shootdown.
+pub struct TlbBatch {
+    pcid: u16,
+    pages: [usize; MAX_BATCH_PAGES],
+    len: usize,
+    flush_all: bool,
+}
+impl TlbBatch {
+    /// Empty batch for a memory map tagged with `pcid`
+    pub fn new(pcid: u16) -> Self {
+        Self {
+            pcid,
+            pages: [0; MAX_BATCH_PAGES],
+            len: 0,
+            flush_all: false,
+        }
+    }
+    /// Adds the page containing `address`, huge pages only need to be added once
+    pub fn push(&mut self, address: usize) {
+        if self.len == MAX_BATCH_PAGES {
+            self.flush_all = true;
+        } else {
+            self.pages[self.len] = address;
+            self.len += 1;
+        }
+    }
+    /// Invalidates the pages on this core and waits until every other online core did the same
+    pub fn flush(self) {
+        if self.len == 0 {
+            return;
+        }
+        if !smp::other_cores_online() && !PCID_ENABLED.load(Ordering::Relaxed) {
+            // Only this core can have cached them
+            if self.flush_all {
+                tlb::flush_all();
+            } else {
+                for &address in &self.pages[..self.len] {
+                    tlb::flush(VirtAddr::new(address as u64));
+                }
+            }
+            return;
+        }
+        // Waiting with interrupts disabled is fine, shootdowns sent to this core are done while waiting,
+        // and cores waiting for a lock held across this, like the kernel memory map's, do theirs while
+        // waiting for it with a `CoreMutex`
+        interrupts::without_interrupts(|| {
+            let this_core = current_core_id();
+            {
+                let mut queue = QUEUES[this_core].lock();
+                if self.flush_all {
+                    queue.invalidate_everything();
+                } else {
+                    for &address in &self.pages[..self.len] {
+                        queue.invalidate(address, self.pcid);
+                    }
+                }
+            }
+            let mut requested = [0; MAX_CORES];
+            let other_cores = || smp::online_cores().filter(|&core| core != this_core);
+            for core in other_cores() {
+                let mut queue = QUEUES[core].lock();
+                queue.push(&self);
+                requested[core] = queue.requested;
+            }
+            {
+                let mut lapic = LAPIC.write();
+                for core in other_cores() {
+                    unsafe { lapic.send_ipi(TLB_SHOOTDOWN_INTERRUPT_ID, core as u32) };
+                }
+            }
+            for core in other_cores() {
+                while COMPLETED[core].load(Ordering::Acquire) < requested[core] {
+                    QUEUES[this_core].lock().process(this_core);
+                    spin_loop();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        arch::x86_64::idt::probe_read,
+        bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+        kernel::{
+            memory_map::{MemoryFlags, MemoryMap},
+            virtual_memory, KERNEL_MEMORY_MAP,
+        },
+    };
+
+    static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Reads the byte at [`ADDRESS`], or returns `usize::MAX` if it faults
+    fn read_address() -> usize {
+        let mut value = 0;
+        if unsafe { probe_read(ADDRESS.load(Ordering::Relaxed) as *const u8, &mut value) } {
+            value as usize
+        } else {
+            usize::MAX
+        }
+    }
+
+    #[test(name = "A page unmapped on one core faults on another core that cached it")]
+    fn test_tlb_shootdown() {
+        let Some(other_core) = smp::online_cores().find(|&core| core != current_core_id()) else {
+            println!("Only one core is online, skipping");
+            return;
+        };
+        let frame = GLOBAL_PAGE_ALLOCATOR.lock().request_page().unwrap().get();
+        let region = virtual_memory::reserve(PAGE_SIZE).unwrap();
+        unsafe {
+            assert!(KERNEL_MEMORY_MAP.lock().map_memory(
+                region.start(),
+                frame,
+                MemoryFlags::KERNEL_DATA
+            ));
+            (region.start() as *mut u8).write_volatile(0x42);
+        }
+        ADDRESS.store(region.start(), Ordering::Relaxed);
+        // The other core caches the translation, the page fault it takes later must not find the map locked
+        assert_eq!(smp::run_on_core(other_core, read_address), 0x42);
+        unsafe { KERNEL_MEMORY_MAP.lock().unmap_memory(region.start()) };
+        assert_eq!(smp::run_on_core(other_core, read_address), usize::MAX);
+        GLOBAL_PAGE_ALLOCATOR.lock().free_pages(frame, PAGE_SIZE);
+        virtual_memory::release(region);
+    }
+
+    #[test(name = "PCIDs are handed out once until they are released")]
+    fn test_pcid_set() {
+        let mut set = PcidSet::with_shared();
+        assert_eq!(set.take_free(), Some(1));
+        assert_eq!(set.take_free(), Some(2));
+        assert!(set.remove(1));
+        assert!(!set.remove(1));
+        assert_eq!(set.take_free(), Some(1));
+        set.insert_all();
+        assert_eq!(set.take_free(), None);
+    }
+}
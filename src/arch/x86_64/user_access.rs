@@ -0,0 +1,142 @@
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use raw_cpuid::CpuId;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+/// Userspace lives in the lower half, everything from here on belongs to the kernel
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Makes the kernel fault when it executes (SMEP) or accesses (SMAP) user memory, every core must call this
+///
+/// User memory can still be accessed through [`copy_from_user`] and [`copy_to_user`].
+///
+/// # Safety
+/// Nothing may rely on accessing user memory directly anymore
+pub unsafe fn enable_user_memory_protection() {
+    let Some(features) = CpuId::new().get_extended_feature_info() else {
+        return;
+    };
+    let mut flags = Cr4::read();
+    if features.has_smep() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features.has_smap() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+    Cr4::write(flags);
+}
+
+/// Lets the kernel access user memory for as long as it lives
+struct UserAccessGuard;
+impl UserAccessGuard {
+    fn new() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            // Memory accesses must not be moved out of the guarded section, so this isn't `nomem`
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        Self
+    }
+}
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+/// Whether `length` bytes starting at `address` are all in userspace
+pub fn is_user_range(address: usize, length: usize) -> bool {
+    address
+        .checked_add(length)
+        .is_some_and(|end| end <= USER_SPACE_END)
+}
+
+/// Copies `destination.len()` bytes from the user address `source`, returns false if it isn't a user range
+///
+/// # Safety
+/// The user memory must be mapped in the current address space
+pub unsafe fn copy_from_user(destination: &mut [u8], source: usize) -> bool {
+    if !is_user_range(source, destination.len()) {
+        return false;
+    }
+    let _guard = UserAccessGuard::new();
+    core::ptr::copy_nonoverlapping(
+        source as *const u8,
+        destination.as_mut_ptr(),
+        destination.len(),
+    );
+    true
+}
+
+/// Copies `source` to the user address `destination`, returns false if it isn't a user range
+///
+/// # Safety
+/// The user memory must be mapped as writable in the current address space
+pub unsafe fn copy_to_user(destination: usize, source: &[u8]) -> bool {
+    if !is_user_range(destination, source.len()) {
+        return false;
+    }
+    let _guard = UserAccessGuard::new();
+    core::ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len());
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+        kernel::{
+            memory_map::{physical_to_virtual, MemoryFlags, MemoryMap},
+            KERNEL_MEMORY_MAP,
+        },
+    };
+
+    #[test(name = "SMEP and SMAP are enabled when the CPU has them")]
+    fn test_user_memory_protection() {
+        let features = CpuId::new().get_extended_feature_info().unwrap();
+        let flags = Cr4::read();
+        assert_eq!(
+            flags.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+            features.has_smep()
+        );
+        assert_eq!(
+            flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+            features.has_smap()
+        );
+    }
+
+    #[test(name = "Copy to and from user memory")]
+    fn test_copy_user_memory() {
+        const USER_ADDRESS: usize = 0x60_0000;
+        let frame = GLOBAL_PAGE_ALLOCATOR.lock().request_page().unwrap().get();
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        unsafe {
+            assert!(mapper.map_memory(USER_ADDRESS, frame, MemoryFlags::USER_DATA));
+            assert!(copy_to_user(USER_ADDRESS + 8, b"user data"));
+            assert_eq!(
+                core::slice::from_raw_parts((physical_to_virtual(frame) + 8) as *const u8, 9),
+                b"user data"
+            );
+            let mut copied = [0u8; 9];
+            assert!(copy_from_user(&mut copied, USER_ADDRESS + 8));
+            assert_eq!(&copied, b"user data");
+
+            // Kernel addresses and ranges crossing into the kernel are refused
+            let kernel_address = physical_to_virtual(frame);
+            assert!(!copy_from_user(&mut copied, kernel_address));
+            assert!(!copy_to_user(USER_SPACE_END - 4, b"user data"));
+            assert!(!copy_to_user(usize::MAX - 4, b"user data"));
+            mapper.unmap_memory(USER_ADDRESS);
+        }
+        drop(mapper);
+        GLOBAL_PAGE_ALLOCATOR.lock().free_pages(frame, PAGE_SIZE);
+    }
+}
@@ -4,8 +4,10 @@ use lazy_static::lazy_static;
 use limine::memory_map::EntryType;
 use spin::Mutex;
 
-use crate::limine::MEMMAP_REQ;
+use crate::{kernel::memory_map::physical_to_virtual, limine::MEMMAP_REQ};
 pub const PAGE_SIZE: usize = 0x1000;
+/// Maximum number of memory map entries the page allocator can keep track of
+pub const MAX_MEMORY_REGIONS: usize = 128;
 pub struct BitMap<'a> {
     bitmap: &'a mut [u8],
 }
@@ -25,7 +27,7 @@ impl<'a> BitMap<'a> {
         self.bitmap[div_index] = if value {
             self.bitmap[div_index] | (0b10000000u8 >> offset)
         } else {
-            self.bitmap[div_index] ^ (0b10000000u8 >> offset)
+            self.bitmap[div_index] & !(0b10000000u8 >> offset)
         };
         true
     }
@@ -46,7 +48,7 @@ impl<'a> BitMap<'a> {
             return false;
         }
         let offset = index % 8;
-        self.bitmap[div_index] ^= 0b10000000 >> offset;
+        self.bitmap[div_index] &= !(0b10000000 >> offset);
         true
     }
     pub fn cfg(&mut self, index: usize, value: bool) {
@@ -90,110 +92,384 @@ impl<'a> BitMap<'a> {
         Some(masked_byte >= 1)
     }
 }
-pub struct BitmapAllocator<'a> {
+/// A physically contiguous range of memory whose pages are tracked by their own bitmap
+pub struct MemoryRegion<'a> {
     bitmap: BitMap<'a>,
-    memory_region_start: usize,
-    memory_region_size: usize,
+    /// References every used page has besides the one of its owner, see [`BitmapAllocator::add_reference`]
+    shares: &'a mut [u16],
+    start: usize,
+    size: usize,
+    free_pages: usize,
     last_allocated_page_index: usize,
 }
+impl MemoryRegion<'_> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+    pub fn number_of_pages(&self) -> usize {
+        self.size.div_floor(PAGE_SIZE)
+    }
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && (addr - self.start) < self.number_of_pages() * PAGE_SIZE
+    }
+    fn page_index(&self, addr: usize) -> usize {
+        (addr - self.start).div_floor(PAGE_SIZE)
+    }
+    fn page_address(&self, index: usize) -> usize {
+        self.start + (index * PAGE_SIZE)
+    }
+    fn lock_page(&mut self, index: usize) {
+        if !self.bitmap.get(index) {
+            self.bitmap.set(index);
+            self.free_pages -= 1;
+        }
+    }
+    fn free_page(&mut self, index: usize) {
+        if self.bitmap.get(index) {
+            self.bitmap.clear(index);
+            self.free_pages += 1;
+        }
+    }
+    fn request_page(&mut self) -> Option<usize> {
+        if self.free_pages == 0 {
+            return None;
+        }
+        for i in (self.last_allocated_page_index..self.number_of_pages())
+            .chain(0..self.last_allocated_page_index)
+        {
+            if self.bitmap.get(i) {
+                continue;
+            }
+            self.lock_page(i);
+            self.last_allocated_page_index = i;
+            return Some(self.page_address(i));
+        }
+        None
+    }
+    /// Finds `count` free pages in a row starting at an address aligned to `align`
+    /// whose last byte is at or below `max_physical_address`, and marks them as used
+    fn request_pages(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_physical_address: usize,
+    ) -> Option<usize> {
+        if self.free_pages < count {
+            return None;
+        }
+        let mut candidate = self.start.next_multiple_of(align);
+        loop {
+            let first_page = self.page_index(candidate);
+            let end_page = first_page.checked_add(count)?;
+            if end_page > self.number_of_pages()
+                || self.page_address(end_page) - 1 > max_physical_address
+            {
+                return None;
+            }
+            // Skip past the last used page in the candidate run instead of retrying every page
+            match (first_page..end_page).rev().find(|&i| self.bitmap.get(i)) {
+                Some(used_page) => {
+                    candidate = self.page_address(used_page + 1).next_multiple_of(align)
+                }
+                None => break,
+            }
+        }
+        let first_page = self.page_index(candidate);
+        for i in first_page..first_page + count {
+            self.lock_page(i);
+        }
+        Some(candidate)
+    }
+}
+
+pub struct BitmapAllocator<'a> {
+    regions: [Option<MemoryRegion<'a>>; MAX_MEMORY_REGIONS],
+    last_allocated_region_index: usize,
+}
 impl<'a> BitmapAllocator<'a> {
+    /// Creates an allocator that doesn't manage any memory yet, use [`BitmapAllocator::add_region`] to give it memory
+    pub const fn new() -> Self {
+        Self {
+            regions: [const { None }; MAX_MEMORY_REGIONS],
+            last_allocated_region_index: 0,
+        }
+    }
+    /// Number of bytes the bitmap of a region with `size` bytes needs
+    pub const fn bitmap_size(size: usize) -> usize {
+        (size / PAGE_SIZE).div_ceil(8)
+    }
+    /// Number of reference counts a region with `size` bytes needs
+    pub const fn shares_len(size: usize) -> usize {
+        size / PAGE_SIZE
+    }
+    /// Adds a region of free memory to the allocator, returns false if there is no room left for more regions
+    ///
+    /// `bitmap` must be at least [`BitmapAllocator::bitmap_size`] bytes long
+    /// and `shares` at least [`BitmapAllocator::shares_len`] entries long
+    pub fn add_region(
+        &mut self,
+        start: usize,
+        size: usize,
+        bitmap: &'a mut [u8],
+        shares: &'a mut [u16],
+    ) -> bool {
+        assert!(
+            bitmap.len() >= Self::bitmap_size(size),
+            "Bitmap is too small for a region of {size} bytes"
+        );
+        assert!(
+            shares.len() >= Self::shares_len(size),
+            "Reference counts are too small for a region of {size} bytes"
+        );
+        assert!(
+            start % PAGE_SIZE == 0,
+            "Memory regions must be page aligned"
+        );
+        let Some(slot) = self.regions.iter_mut().find(|r| r.is_none()) else {
+            return false;
+        };
+        bitmap.fill(0);
+        shares.fill(0);
+        let mut region = MemoryRegion {
+            bitmap: BitMap::new(bitmap),
+            shares,
+            start,
+            size,
+            free_pages: 0,
+            last_allocated_page_index: 0,
+        };
+        region.free_pages = region.number_of_pages();
+        *slot = Some(region);
+        true
+    }
+    pub fn regions(&self) -> impl Iterator<Item = &MemoryRegion<'a>> {
+        self.regions.iter().flatten()
+    }
+    fn region_containing(&mut self, addr: usize) -> Option<&mut MemoryRegion<'a>> {
+        self.regions.iter_mut().flatten().find(|r| r.contains(addr))
+    }
+    /// Total number of pages managed by this allocator
     pub fn number_of_pages(&self) -> usize {
-        self.memory_region_size.div_floor(PAGE_SIZE)
+        self.regions().map(MemoryRegion::number_of_pages).sum()
+    }
+    /// Number of pages that can still be allocated
+    pub fn free_page_count(&self) -> usize {
+        self.regions().map(MemoryRegion::free_pages).sum()
     }
     pub fn from_mmap() -> BitmapAllocator<'static> {
         let memory_map = MEMMAP_REQ
             .get_response()
             .expect("memory map should be available");
-        // dbg!(memory_map.entries().iter().filter(|e| e.entry_type == EntryType::USABLE).map(|e| dbg!(e.length)).sum::<u64>());
-        let Some(&entry) = memory_map
-            .entries()
-            .iter()
-            .filter(|e| e.entry_type == EntryType::USABLE)
-            .max_by_key(|entry| entry.length)
+        // Bootloader reclaimable memory stays with the bootloader, the kernel keeps using its page tables
+        let managed_entries = || {
+            memory_map
+                .entries()
+                .iter()
+                .filter(|e| e.entry_type == EntryType::USABLE)
+        };
+        // All the reference counts and then all the bitmaps are stored one after the other
+        // at the start of the first usable region that can fit them
+        let shares_len: usize = managed_entries()
+            .map(|e| Self::shares_len(e.length as usize))
+            .sum();
+        let shares_size = shares_len * size_of::<u16>();
+        let bitmaps_size: usize = managed_entries()
+            .map(|e| Self::bitmap_size(e.length as usize))
+            .sum();
+        let Some(&bitmaps_entry) =
+            managed_entries().find(|e| e.length as usize >= shares_size + bitmaps_size)
         else {
             panic!("Couldn't find a usable memory region")
         };
-        let mut allocator = BitmapAllocator {
-            bitmap: unsafe {
-                BitMap::new({
-                    let bitmap_slice = core::slice::from_raw_parts_mut(
-                        entry.base as usize as *mut u8,
-                        (entry.length as usize).div_ceil(PAGE_SIZE * 8),
-                    );
-                    bitmap_slice.fill(0);
-                    bitmap_slice
-                })
-            },
-            memory_region_size: entry.length as usize,
-            memory_region_start: entry.base as usize,
-            last_allocated_page_index: 0,
+        let mut shares = unsafe {
+            core::slice::from_raw_parts_mut(
+                physical_to_virtual(bitmaps_entry.base as usize) as *mut u16,
+                shares_len,
+            )
         };
+        let mut bitmaps = unsafe {
+            core::slice::from_raw_parts_mut(
+                physical_to_virtual(bitmaps_entry.base as usize + shares_size) as *mut u8,
+                bitmaps_size,
+            )
+        };
+        let mut allocator = BitmapAllocator::new();
+        for entry in managed_entries() {
+            let (bitmap, rest) = core::mem::take(&mut bitmaps)
+                .split_at_mut(Self::bitmap_size(entry.length as usize));
+            bitmaps = rest;
+            let (region_shares, rest) =
+                core::mem::take(&mut shares).split_at_mut(Self::shares_len(entry.length as usize));
+            shares = rest;
+            assert!(
+                allocator.add_region(
+                    entry.base as usize,
+                    entry.length as usize,
+                    bitmap,
+                    region_shares,
+                ),
+                "The memory map has more than {MAX_MEMORY_REGIONS} usable regions"
+            );
+        }
+        allocator.lock_pages(bitmaps_entry.base as usize, shares_size + bitmaps_size);
         println!(
-            "memory_region_start: {}, memory_region_size: {}",
-            allocator.memory_region_start, allocator.memory_region_size
-        );
-        allocator.lock_pages(
-            allocator.memory_region_start,
-            allocator.number_of_pages() / 8,
+            "Physical page allocator: {} KiB free in {} regions",
+            allocator.free_page_count() * PAGE_SIZE / 1024,
+            allocator.regions().count()
         );
         allocator
     }
+    /// Marks every page in `addr..addr + size` as used, pages outside the managed regions are ignored
     pub fn lock_pages(&mut self, addr: usize, size: usize) {
-        let rel_addr = addr as usize - self.memory_region_start;
-        let page = rel_addr.div_floor(PAGE_SIZE);
-        let page_end = page + (size.div_ceil(PAGE_SIZE));
-        for i in page..=page_end {
-            self.bitmap.try_set(i);
+        let first_page = addr.div_floor(PAGE_SIZE);
+        for page in first_page..(addr + size).div_ceil(PAGE_SIZE) {
+            if let Some(region) = self.region_containing(page * PAGE_SIZE) {
+                let index = region.page_index(page * PAGE_SIZE);
+                region.lock_page(index);
+            }
         }
-        self.last_allocated_page_index = page;
     }
+    /// Marks every page in `addr..addr + size` as free, pages outside the managed regions are ignored
+    ///
+    /// # Panics
+    /// Panics if one of the pages is shared, those are freed with [`BitmapAllocator::release_page`]
     pub fn free_pages(&mut self, addr: usize, size: usize) {
-        let rel_addr = addr - self.memory_region_start;
-        let page = rel_addr.div_floor(PAGE_SIZE);
-        let page_end = page + (size.div_ceil(PAGE_SIZE));
-        for i in page..=page_end {
-            self.bitmap.try_clear(i);
+        let first_page = addr.div_floor(PAGE_SIZE);
+        for page in first_page..(addr + size).div_ceil(PAGE_SIZE) {
+            if let Some(region) = self.region_containing(page * PAGE_SIZE) {
+                let index = region.page_index(page * PAGE_SIZE);
+                assert!(
+                    region.shares[index] == 0,
+                    "Tried to free shared page 0x{:X}",
+                    page * PAGE_SIZE
+                );
+                region.free_page(index);
+            }
         }
-
-        self.last_allocated_page_index = page;
+    }
+    /// Adds a reference to the used page at `addr`, it is only freed once every reference is released
+    /// with [`BitmapAllocator::release_page`]
+    pub fn add_reference(&mut self, addr: usize) {
+        let region = self
+            .region_containing(addr)
+            .expect("Only pages of the allocator can be shared");
+        let index = region.page_index(addr);
+        assert!(
+            region.bitmap.get(index),
+            "Tried to share free page 0x{addr:X}"
+        );
+        region.shares[index] = region.shares[index]
+            .checked_add(1)
+            .expect("Page has too many references");
+    }
+    /// Number of references to the page at `addr`, 0 if it is free or not managed by the allocator
+    pub fn references(&self, addr: usize) -> usize {
+        self.regions()
+            .find(|r| r.contains(addr))
+            .map(|region| {
+                let index = region.page_index(addr);
+                if region.bitmap.get(index) {
+                    region.shares[index] as usize + 1
+                } else {
+                    0
+                }
+            })
+            .unwrap_or(0)
+    }
+    /// Drops a reference to the page at `addr`, returns true if it was the last one and the page got freed
+    ///
+    /// Returns false for pages the allocator doesn't manage, nothing is freed then.
+    pub fn release_page(&mut self, addr: usize) -> bool {
+        let Some(region) = self.region_containing(addr) else {
+            return false;
+        };
+        let index = region.page_index(addr);
+        if region.shares[index] > 0 {
+            region.shares[index] -= 1;
+            return false;
+        }
+        self.free_pages(addr, PAGE_SIZE);
+        true
     }
     pub fn request_page(&mut self) -> Option<NonZeroUsize> {
-        for i in (self.last_allocated_page_index..self.number_of_pages())
-            .chain(0..self.last_allocated_page_index)
+        let region_count = self.regions.len();
+        for i in (self.last_allocated_region_index..region_count)
+            .chain(0..self.last_allocated_region_index)
         {
-            if self.bitmap.get(i) {
+            let Some(region) = &mut self.regions[i] else {
                 continue;
+            };
+            if let Some(addr) = region.request_page() {
+                self.last_allocated_region_index = i;
+                return Some(NonZeroUsize::new(addr).unwrap());
             }
-            let addr = self.memory_region_start + (i * PAGE_SIZE);
-            assert!(
-                addr - self.memory_region_start <= self.memory_region_size,
-                "addr: 0x{addr:X} | self.memory_region_start: 0x{:X} | self.memory_region_size: 0x{:X}",
-                self.memory_region_start,
-                self.memory_region_size
-            );
-            self.lock_pages(addr, PAGE_SIZE);
-            self.last_allocated_page_index = i;
-            return Some(NonZeroUsize::new(addr).unwrap());
         }
         None
     }
+    /// Allocates `count` physically contiguous pages
+    ///
+    /// The first page is aligned to `align` bytes (a power of two, at least [`PAGE_SIZE`])
+    /// and the whole run ends at or below `max_physical_address`, which is useful for devices
+    /// that can only address part of the memory. Free them with [`BitmapAllocator::free_contiguous_pages`].
+    pub fn request_pages(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_physical_address: usize,
+    ) -> Option<NonZeroUsize> {
+        assert!(
+            align.is_power_of_two() && align >= PAGE_SIZE,
+            "Alignment must be a power of two and at least the page size"
+        );
+        if count == 0 {
+            return None;
+        }
+        let addr = self
+            .regions
+            .iter_mut()
+            .flatten()
+            .find_map(|r| r.request_pages(count, align, max_physical_address))?;
+        NonZeroUsize::new(addr)
+    }
+    /// Frees a run of pages allocated with [`BitmapAllocator::request_pages`]
+    pub fn free_contiguous_pages(&mut self, addr: NonZeroUsize, count: usize) {
+        self.free_pages(addr.get(), count * PAGE_SIZE);
+    }
     pub fn request_and_clear_page(&mut self) -> Option<NonZeroUsize> {
         let page = self.request_page()?;
         // SAFETY: This will just clear the newly allocated page,
         unsafe {
-            let slice = core::slice::from_raw_parts_mut(page.get() as *mut u8, PAGE_SIZE);
-            for b in slice {
-                *b = 0;
-            }
+            let slice = core::slice::from_raw_parts_mut(
+                physical_to_virtual(page.get()) as *mut u8,
+                PAGE_SIZE,
+            );
+            slice.fill(0);
         }
         Some(page)
     }
 }
+impl Default for BitmapAllocator<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 unsafe impl<'a> Send for BitmapAllocator<'a> {}
 
+cfg_if::cfg_if! {
+    if #[cfg(feature = "buddy-allocator")] {
+        /// The physical page allocator used by the kernel
+        pub type PageAllocator = crate::buddy_allocator::BuddyAllocator<'static>;
+    } else {
+        /// The physical page allocator used by the kernel
+        pub type PageAllocator = BitmapAllocator<'static>;
+    }
+}
 lazy_static! {
-    pub static ref GLOBAL_PAGE_ALLOCATOR: Mutex<BitmapAllocator<'static>> =
-        Mutex::new(BitmapAllocator::from_mmap());
+    pub static ref GLOBAL_PAGE_ALLOCATOR: Mutex<PageAllocator> =
+        Mutex::new(PageAllocator::from_mmap());
 }
 #[cfg(test)]
 mod tests {
@@ -223,4 +499,150 @@ mod tests {
         // Index out of bounds, should return None
         assert_eq!(bitmap.try_set(20), false);
     }
+
+    #[test(name = "Clear a bit in a BitMap without touching its neighbours")]
+    fn test_clear_bit() {
+        let mut bitmap_data = [0b00101000u8; 1];
+        let mut bitmap = BitMap::new(&mut bitmap_data);
+        bitmap.clear(2);
+        bitmap.clear(3);
+        assert_eq!(bitmap_data, [0b00001000]);
+    }
+
+    #[test(name = "Allocate pages spread across multiple memory regions")]
+    fn test_multiple_regions() {
+        let mut first_bitmap = [0u8; 1];
+        let mut second_bitmap = [0u8; 1];
+        let mut first_shares = [0u16; 2];
+        let mut second_shares = [0u16; 3];
+        let mut allocator = BitmapAllocator::new();
+        assert!(allocator.add_region(
+            0x10_0000,
+            2 * PAGE_SIZE,
+            &mut first_bitmap,
+            &mut first_shares
+        ));
+        assert!(allocator.add_region(
+            0x20_0000,
+            3 * PAGE_SIZE,
+            &mut second_bitmap,
+            &mut second_shares
+        ));
+        assert_eq!(allocator.number_of_pages(), 5);
+
+        let mut pages = [0usize; 5];
+        for page in pages.iter_mut() {
+            *page = allocator
+                .request_page()
+                .expect("region should have free pages")
+                .get();
+            assert!(allocator.regions().any(|r| r.contains(*page)));
+        }
+        assert_eq!(allocator.request_page(), None);
+        assert_eq!(allocator.free_page_count(), 0);
+
+        allocator.free_pages(pages[3], PAGE_SIZE);
+        assert_eq!(allocator.free_page_count(), 1);
+        assert_eq!(
+            allocator.request_page().map(NonZeroUsize::get),
+            Some(pages[3])
+        );
+    }
+
+    #[test(name = "Allocate contiguous pages in a fragmented region")]
+    fn test_contiguous_pages_fragmentation() {
+        let mut bitmap = [0u8; 1];
+        let mut shares = [0u16; 8];
+        let mut allocator = BitmapAllocator::new();
+        allocator.add_region(0x10_0000, 8 * PAGE_SIZE, &mut bitmap, &mut shares);
+        // Leave only every other page free
+        for page in (0..8).step_by(2) {
+            allocator.lock_pages(0x10_0000 + page * PAGE_SIZE, PAGE_SIZE);
+        }
+        assert_eq!(allocator.request_pages(2, PAGE_SIZE, usize::MAX), None);
+        assert_eq!(
+            allocator
+                .request_pages(1, PAGE_SIZE, usize::MAX)
+                .map(NonZeroUsize::get),
+            Some(0x10_1000)
+        );
+
+        allocator.free_pages(0x10_4000, PAGE_SIZE);
+        let run = allocator
+            .request_pages(3, PAGE_SIZE, usize::MAX)
+            .expect("pages 3 to 5 should be free");
+        assert_eq!(run.get(), 0x10_3000);
+        assert_eq!(allocator.free_page_count(), 1);
+
+        allocator.free_contiguous_pages(run, 3);
+        assert_eq!(allocator.free_page_count(), 4);
+    }
+
+    #[test(name = "Allocate contiguous pages with alignment and address constraints")]
+    fn test_contiguous_pages_alignment() {
+        let mut bitmap = [0u8; 2];
+        let mut shares = [0u16; 16];
+        let mut allocator = BitmapAllocator::new();
+        // The region doesn't start at an aligned address, so the first pages must be skipped
+        allocator.add_region(0x10_1000, 16 * PAGE_SIZE, &mut bitmap, &mut shares);
+        let aligned = allocator
+            .request_pages(4, 0x4000, usize::MAX)
+            .expect("region should fit an aligned run");
+        assert_eq!(aligned.get(), 0x10_4000);
+        assert_eq!(
+            allocator
+                .request_pages(4, 0x4000, usize::MAX)
+                .map(NonZeroUsize::get),
+            Some(0x10_8000)
+        );
+        // 0x10_C000 is the only aligned address left and its run would go past the end of the region
+        assert_eq!(allocator.request_pages(8, 0x4000, usize::MAX), None);
+        // The last byte of the run at 0x10_C000 is 0x10_FFFF, a DMA mask like limit
+        assert_eq!(allocator.request_pages(4, 0x4000, 0x10_FFFE), None);
+        assert_eq!(
+            allocator
+                .request_pages(4, 0x4000, 0x10_FFFF)
+                .map(NonZeroUsize::get),
+            Some(0x10_C000)
+        );
+        assert_eq!(allocator.request_pages(0, PAGE_SIZE, usize::MAX), None);
+    }
+
+    #[test(name = "Shared pages are only freed when their last reference is released")]
+    fn test_page_references() {
+        let mut bitmap = [0u8; 1];
+        let mut shares = [0u16; 4];
+        let mut allocator = BitmapAllocator::new();
+        allocator.add_region(0x10_0000, 4 * PAGE_SIZE, &mut bitmap, &mut shares);
+        let page = allocator.request_page().unwrap().get();
+        assert_eq!(allocator.references(page), 1);
+        allocator.add_reference(page);
+        allocator.add_reference(page);
+        assert_eq!(allocator.references(page), 3);
+
+        assert!(!allocator.release_page(page));
+        assert!(!allocator.release_page(page));
+        assert_eq!(allocator.free_page_count(), 3);
+        assert!(allocator.release_page(page));
+        assert_eq!(allocator.references(page), 0);
+        assert_eq!(allocator.free_page_count(), 4);
+        // Pages outside the regions were never allocated, so releasing them frees nothing
+        assert!(!allocator.release_page(0x20_0000));
+        assert_eq!(allocator.free_page_count(), 4);
+    }
+
+    #[test(name = "Page allocator manages every usable memory map entry")]
+    fn test_allocator_covers_memory_map() {
+        let usable_pages: usize = MEMMAP_REQ
+            .get_response()
+            .unwrap()
+            .entries()
+            .iter()
+            .filter(|e| e.entry_type == EntryType::USABLE)
+            .map(|e| e.length as usize / PAGE_SIZE)
+            .sum();
+        let allocator = GLOBAL_PAGE_ALLOCATOR.lock();
+        assert!(allocator.number_of_pages() >= usable_pages);
+        assert!(allocator.free_page_count() >= usable_pages * 9 / 10);
+    }
 }
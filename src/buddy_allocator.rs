@@ -0,0 +1,495 @@
+use core::num::NonZeroUsize;
+
+use limine::memory_map::EntryType;
+
+use crate::{
+    bitmap_allocator::{MAX_MEMORY_REGIONS, PAGE_SIZE},
+    kernel::memory_map::physical_to_virtual,
+    limine::MEMMAP_REQ,
+};
+
+/// Biggest block the buddy allocator hands out, 2^18 pages (1 GiB)
+pub const MAX_ORDER: usize = 18;
+const NO_FRAME: u32 = u32::MAX;
+
+/// Bookkeeping the buddy allocator keeps for every page it manages
+///
+/// Only the first page of a free block is meaningful, it links the block into the free list of its order
+#[derive(Clone, Copy)]
+pub struct FrameInfo {
+    next: u32,
+    prev: u32,
+    order: u8,
+    free: bool,
+    /// References a used page has besides the one of its owner, see [`BuddyAllocator::add_reference`]
+    shares: u16,
+}
+impl FrameInfo {
+    pub const UNUSED: Self = Self {
+        next: NO_FRAME,
+        prev: NO_FRAME,
+        order: 0,
+        free: false,
+        shares: 0,
+    };
+}
+
+struct BuddyRegion<'a> {
+    frames: &'a mut [FrameInfo],
+    /// Physical frame number of the first page in this region
+    first_frame: usize,
+    free_lists: [u32; MAX_ORDER + 1],
+    free_pages: usize,
+}
+impl BuddyRegion<'_> {
+    fn number_of_pages(&self) -> usize {
+        self.frames.len()
+    }
+    fn contains(&self, addr: usize) -> bool {
+        let frame = addr / PAGE_SIZE;
+        frame >= self.first_frame && frame - self.first_frame < self.number_of_pages()
+    }
+    fn push(&mut self, index: usize, order: usize) {
+        let head = self.free_lists[order];
+        self.frames[index] = FrameInfo {
+            next: head,
+            prev: NO_FRAME,
+            order: order as u8,
+            free: true,
+            shares: 0,
+        };
+        if head != NO_FRAME {
+            self.frames[head as usize].prev = index as u32;
+        }
+        self.free_lists[order] = index as u32;
+    }
+    fn remove(&mut self, index: usize) {
+        let FrameInfo {
+            next, prev, order, ..
+        } = self.frames[index];
+        if prev == NO_FRAME {
+            self.free_lists[order as usize] = next;
+        } else {
+            self.frames[prev as usize].next = next;
+        }
+        if next != NO_FRAME {
+            self.frames[next as usize].prev = prev;
+        }
+        self.frames[index] = FrameInfo::UNUSED;
+    }
+    /// Returns a block to the free lists, merging it with its buddy for as long as the buddy is free
+    fn free_block(&mut self, mut index: usize, mut order: usize) {
+        self.free_pages += 1 << order;
+        while order < MAX_ORDER {
+            let buddy_frame = (self.first_frame + index) ^ (1 << order);
+            let Some(buddy) = buddy_frame.checked_sub(self.first_frame) else {
+                break;
+            };
+            if buddy + (1 << order) > self.number_of_pages()
+                || !self.frames[buddy].free
+                || self.frames[buddy].order as usize != order
+            {
+                break;
+            }
+            self.remove(buddy);
+            index = index.min(buddy);
+            order += 1;
+        }
+        self.push(index, order);
+    }
+    /// Frees `count` pages starting at `index`, splitting them into the biggest aligned blocks possible
+    fn free_range(&mut self, index: usize, count: usize) {
+        let mut frame = self.first_frame + index;
+        let end = frame + count;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(frame - self.first_frame, order);
+            frame += 1 << order;
+        }
+    }
+    /// Finds the free block that contains the page at `index`
+    fn free_block_containing(&self, index: usize) -> Option<(usize, usize)> {
+        let frame = self.first_frame + index;
+        for order in 0..=MAX_ORDER {
+            let head = (frame & !((1 << order) - 1)).checked_sub(self.first_frame)?;
+            let info = self.frames[head];
+            if info.free && info.order as usize == order {
+                return Some((head, order));
+            }
+        }
+        None
+    }
+    fn lock_page(&mut self, index: usize) {
+        let Some((head, order)) = self.free_block_containing(index) else {
+            return;
+        };
+        self.remove(head);
+        self.free_pages -= 1 << order;
+        // Give back the rest of the block around the locked page
+        self.free_range(head, index - head);
+        self.free_range(index + 1, head + (1 << order) - index - 1);
+    }
+    /// Takes a block of `2^order` pages whose first `needed_pages` end before `max_frame_end`
+    fn allocate_block(
+        &mut self,
+        order: usize,
+        needed_pages: usize,
+        max_frame_end: usize,
+    ) -> Option<usize> {
+        for current_order in order..=MAX_ORDER {
+            let mut candidate = self.free_lists[current_order];
+            while candidate != NO_FRAME
+                && self.first_frame + candidate as usize + needed_pages > max_frame_end
+            {
+                candidate = self.frames[candidate as usize].next;
+            }
+            if candidate == NO_FRAME {
+                continue;
+            }
+            let index = candidate as usize;
+            self.remove(index);
+            // Split the block, handing the upper halves back until it has the requested size
+            for split_order in (order..current_order).rev() {
+                self.push(index + (1 << split_order), split_order);
+            }
+            self.free_pages -= 1 << order;
+            return Some(index);
+        }
+        None
+    }
+}
+
+/// Physical page allocator based on the buddy system
+///
+/// Memory is split into power of two blocks, allocating and freeing a block takes `O(log n)`
+/// and freed blocks are merged back with their buddies right away. This is a drop-in replacement
+/// for [`crate::bitmap_allocator::BitmapAllocator`], enabled with the `buddy-allocator` feature.
+pub struct BuddyAllocator<'a> {
+    regions: [Option<BuddyRegion<'a>>; MAX_MEMORY_REGIONS],
+}
+impl<'a> BuddyAllocator<'a> {
+    /// Creates an allocator that doesn't manage any memory yet, use [`BuddyAllocator::add_region`] to give it memory
+    pub const fn new() -> Self {
+        Self {
+            regions: [const { None }; MAX_MEMORY_REGIONS],
+        }
+    }
+    /// Number of [`FrameInfo`] entries a region with `size` bytes needs
+    pub const fn metadata_len(size: usize) -> usize {
+        size / PAGE_SIZE
+    }
+    /// Adds a region of free memory to the allocator, returns false if there is no room left for more regions
+    ///
+    /// `frames` must have exactly [`BuddyAllocator::metadata_len`] entries
+    pub fn add_region(&mut self, start: usize, size: usize, frames: &'a mut [FrameInfo]) -> bool {
+        assert_eq!(
+            frames.len(),
+            Self::metadata_len(size),
+            "Frame metadata doesn't match a region of {size} bytes"
+        );
+        assert!(
+            start % PAGE_SIZE == 0,
+            "Memory regions must be page aligned"
+        );
+        assert!(
+            frames.len() < NO_FRAME as usize,
+            "Memory region is too big for the buddy allocator"
+        );
+        let Some(slot) = self.regions.iter_mut().find(|r| r.is_none()) else {
+            return false;
+        };
+        frames.fill(FrameInfo::UNUSED);
+        let region = slot.insert(BuddyRegion {
+            frames,
+            first_frame: start / PAGE_SIZE,
+            free_lists: [NO_FRAME; MAX_ORDER + 1],
+            free_pages: 0,
+        });
+        region.free_range(0, region.number_of_pages());
+        true
+    }
+    fn regions(&self) -> impl Iterator<Item = &BuddyRegion<'a>> {
+        self.regions.iter().flatten()
+    }
+    fn region_containing(&mut self, addr: usize) -> Option<&mut BuddyRegion<'a>> {
+        self.regions.iter_mut().flatten().find(|r| r.contains(addr))
+    }
+    /// Total number of pages managed by this allocator
+    pub fn number_of_pages(&self) -> usize {
+        self.regions().map(BuddyRegion::number_of_pages).sum()
+    }
+    /// Number of pages that can still be allocated
+    pub fn free_page_count(&self) -> usize {
+        self.regions().map(|r| r.free_pages).sum()
+    }
+    pub fn from_mmap() -> BuddyAllocator<'static> {
+        let memory_map = MEMMAP_REQ
+            .get_response()
+            .expect("memory map should be available");
+        // Bootloader reclaimable memory stays with the bootloader, the kernel keeps using its page tables
+        let managed_entries = || {
+            memory_map
+                .entries()
+                .iter()
+                .filter(|e| e.entry_type == EntryType::USABLE)
+        };
+        // The metadata of every region is stored at the start of the first usable region that can fit it
+        let metadata_len: usize = managed_entries()
+            .map(|e| Self::metadata_len(e.length as usize))
+            .sum();
+        let metadata_size = metadata_len * size_of::<FrameInfo>();
+        let Some(&metadata_entry) = managed_entries().find(|e| e.length as usize >= metadata_size)
+        else {
+            panic!("Couldn't find a usable memory region")
+        };
+        let mut metadata = unsafe {
+            core::slice::from_raw_parts_mut(
+                physical_to_virtual(metadata_entry.base as usize) as *mut FrameInfo,
+                metadata_len,
+            )
+        };
+        let mut allocator = BuddyAllocator::new();
+        for entry in managed_entries() {
+            let (frames, rest) = core::mem::take(&mut metadata)
+                .split_at_mut(Self::metadata_len(entry.length as usize));
+            metadata = rest;
+            assert!(
+                allocator.add_region(entry.base as usize, entry.length as usize, frames),
+                "The memory map has more than {MAX_MEMORY_REGIONS} usable regions"
+            );
+        }
+        allocator.lock_pages(metadata_entry.base as usize, metadata_size);
+        println!(
+            "Buddy page allocator: {} KiB free in {} regions",
+            allocator.free_page_count() * PAGE_SIZE / 1024,
+            allocator.regions().count()
+        );
+        allocator
+    }
+    /// Marks every page in `addr..addr + size` as used, pages outside the managed regions are ignored
+    pub fn lock_pages(&mut self, addr: usize, size: usize) {
+        for page in addr / PAGE_SIZE..(addr + size).div_ceil(PAGE_SIZE) {
+            if let Some(region) = self.region_containing(page * PAGE_SIZE) {
+                let index = page - region.first_frame;
+                region.lock_page(index);
+            }
+        }
+    }
+    /// Adds a reference to the used page at `addr`, it is only freed once every reference is released
+    /// with [`BuddyAllocator::release_page`]
+    pub fn add_reference(&mut self, addr: usize) {
+        let region = self
+            .region_containing(addr)
+            .expect("Only pages of the allocator can be shared");
+        let index = addr / PAGE_SIZE - region.first_frame;
+        assert!(
+            region.free_block_containing(index).is_none(),
+            "Tried to share free page 0x{addr:X}"
+        );
+        region.frames[index].shares = region.frames[index]
+            .shares
+            .checked_add(1)
+            .expect("Page has too many references");
+    }
+    /// Number of references to the page at `addr`, 0 if it is free or not managed by the allocator
+    pub fn references(&self, addr: usize) -> usize {
+        self.regions()
+            .find(|r| r.contains(addr))
+            .map(|region| {
+                let index = addr / PAGE_SIZE - region.first_frame;
+                if region.free_block_containing(index).is_some() {
+                    0
+                } else {
+                    region.frames[index].shares as usize + 1
+                }
+            })
+            .unwrap_or(0)
+    }
+    /// Drops a reference to the page at `addr`, returns true if it was the last one and the page got freed
+    ///
+    /// Returns false for pages the allocator doesn't manage, nothing is freed then.
+    pub fn release_page(&mut self, addr: usize) -> bool {
+        let Some(region) = self.region_containing(addr) else {
+            return false;
+        };
+        let index = addr / PAGE_SIZE - region.first_frame;
+        if region.frames[index].shares > 0 {
+            region.frames[index].shares -= 1;
+            return false;
+        }
+        self.free_pages(addr, PAGE_SIZE);
+        true
+    }
+    /// Marks every page in `addr..addr + size` as free, pages outside the managed regions are ignored
+    ///
+    /// # Panics
+    /// Panics if one of the pages is already free or shared
+    pub fn free_pages(&mut self, addr: usize, size: usize) {
+        let mut page = addr / PAGE_SIZE;
+        let end = (addr + size).div_ceil(PAGE_SIZE);
+        while page < end {
+            let Some(region) = self.region_containing(page * PAGE_SIZE) else {
+                page += 1;
+                continue;
+            };
+            let index = page - region.first_frame;
+            let count = (end - page).min(region.number_of_pages() - index);
+            for i in index..index + count {
+                assert!(
+                    region.free_block_containing(i).is_none(),
+                    "Tried to free page 0x{:X} twice",
+                    (region.first_frame + i) * PAGE_SIZE
+                );
+                assert!(
+                    region.frames[i].shares == 0,
+                    "Tried to free shared page 0x{:X}",
+                    (region.first_frame + i) * PAGE_SIZE
+                );
+            }
+            region.free_range(index, count);
+            page += count;
+        }
+    }
+    pub fn request_page(&mut self) -> Option<NonZeroUsize> {
+        self.request_pages(1, PAGE_SIZE, usize::MAX)
+    }
+    /// Allocates `count` physically contiguous pages
+    ///
+    /// The first page is aligned to `align` bytes (a power of two, at least [`PAGE_SIZE`])
+    /// and the whole run ends at or below `max_physical_address`. Free them with [`BuddyAllocator::free_contiguous_pages`].
+    pub fn request_pages(
+        &mut self,
+        count: usize,
+        align: usize,
+        max_physical_address: usize,
+    ) -> Option<NonZeroUsize> {
+        assert!(
+            align.is_power_of_two() && align >= PAGE_SIZE,
+            "Alignment must be a power of two and at least the page size"
+        );
+        if count == 0 {
+            return None;
+        }
+        let order =
+            (count.next_power_of_two().ilog2() as usize).max((align / PAGE_SIZE).ilog2() as usize);
+        if order > MAX_ORDER {
+            return None;
+        }
+        // The limit is the last usable byte, like a DMA mask
+        let max_frame_end = max_physical_address.saturating_add(1) / PAGE_SIZE;
+        self.regions.iter_mut().flatten().find_map(|region| {
+            let index = region.allocate_block(order, count, max_frame_end)?;
+            // Only `count` pages were asked for, the tail of the block goes back to the free lists
+            region.free_range(index + count, (1 << order) - count);
+            NonZeroUsize::new((region.first_frame + index) * PAGE_SIZE)
+        })
+    }
+    /// Frees a run of pages allocated with [`BuddyAllocator::request_pages`]
+    pub fn free_contiguous_pages(&mut self, addr: NonZeroUsize, count: usize) {
+        self.free_pages(addr.get(), count * PAGE_SIZE);
+    }
+    pub fn request_and_clear_page(&mut self) -> Option<NonZeroUsize> {
+        let page = self.request_page()?;
+        // SAFETY: This will just clear the newly allocated page,
+        unsafe {
+            let slice = core::slice::from_raw_parts_mut(
+                physical_to_virtual(page.get()) as *mut u8,
+                PAGE_SIZE,
+            );
+            slice.fill(0);
+        }
+        Some(page)
+    }
+}
+impl Default for BuddyAllocator<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+unsafe impl Send for BuddyAllocator<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test(name = "Buddy allocator splits blocks and merges them back on free")]
+    fn test_split_and_coalesce() {
+        let mut frames = [FrameInfo::UNUSED; 16];
+        let mut allocator = BuddyAllocator::new();
+        allocator.add_region(0x10_0000, 16 * PAGE_SIZE, &mut frames);
+        assert_eq!(allocator.free_page_count(), 16);
+
+        let first = allocator.request_page().unwrap();
+        let second = allocator.request_page().unwrap();
+        assert_eq!(first.get(), 0x10_0000);
+        assert_eq!(second.get(), 0x10_1000);
+        assert_eq!(allocator.free_page_count(), 14);
+        // The whole region is split, so there is no room for all 16 pages anymore
+        assert_eq!(allocator.request_pages(16, PAGE_SIZE, usize::MAX), None);
+
+        allocator.free_pages(first.get(), PAGE_SIZE);
+        allocator.free_pages(second.get(), PAGE_SIZE);
+        assert_eq!(allocator.free_page_count(), 16);
+        assert_eq!(
+            allocator
+                .request_pages(16, PAGE_SIZE, usize::MAX)
+                .map(NonZeroUsize::get),
+            Some(0x10_0000)
+        );
+    }
+
+    #[test(name = "Buddy allocator honours alignment and returns the unused tail of a block")]
+    fn test_contiguous_pages() {
+        let mut frames = [FrameInfo::UNUSED; 15];
+        let mut allocator = BuddyAllocator::new();
+        // Starting at frame 0x101 the region is split into blocks of 1, 2, 4 and 8 pages
+        allocator.add_region(0x10_1000, 15 * PAGE_SIZE, &mut frames);
+        let run = allocator
+            .request_pages(3, 0x4000, usize::MAX)
+            .expect("region should fit 3 aligned pages");
+        assert_eq!(run.get() % 0x4000, 0);
+        assert_eq!(allocator.free_page_count(), 12);
+
+        allocator.lock_pages(0x10_8000, PAGE_SIZE);
+        assert_eq!(allocator.free_page_count(), 11);
+        assert_eq!(allocator.request_pages(8, PAGE_SIZE, usize::MAX), None);
+        assert_eq!(allocator.request_pages(2, PAGE_SIZE, 0x10_1000), None);
+        // The limit is inclusive, the page right below it can be used
+        let last = allocator.request_pages(1, PAGE_SIZE, 0x10_1FFF).unwrap();
+        assert_eq!(last.get(), 0x10_1000);
+        allocator.free_contiguous_pages(last, 1);
+
+        allocator.free_contiguous_pages(run, 3);
+        allocator.free_pages(0x10_8000, PAGE_SIZE);
+        assert_eq!(allocator.free_page_count(), 15);
+        assert_eq!(
+            allocator
+                .request_pages(8, PAGE_SIZE, usize::MAX)
+                .map(NonZeroUsize::get),
+            Some(0x10_8000)
+        );
+    }
+
+    #[test(name = "Buddy allocator only frees shared pages when their last reference is released")]
+    fn test_page_references() {
+        let mut frames = [FrameInfo::UNUSED; 4];
+        let mut allocator = BuddyAllocator::new();
+        allocator.add_region(0x10_0000, 4 * PAGE_SIZE, &mut frames);
+        let page = allocator.request_page().unwrap().get();
+        assert_eq!(allocator.references(page), 1);
+        allocator.add_reference(page);
+        assert_eq!(allocator.references(page), 2);
+
+        assert!(!allocator.release_page(page));
+        assert_eq!(allocator.free_page_count(), 3);
+        assert!(allocator.release_page(page));
+        assert_eq!(allocator.references(page), 0);
+        assert_eq!(allocator.free_page_count(), 4);
+        // Pages outside the regions were never allocated, so releasing them frees nothing
+        assert!(!allocator.release_page(0x20_0000));
+        assert_eq!(allocator.free_page_count(), 4);
+    }
+}
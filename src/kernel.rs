@@ -1,13 +1,26 @@
+mod core_cache;
 mod global_allocator;
 mod heap;
+mod slab;
+pub mod clock;
+pub mod interrupts;
+pub mod kaslr;
 pub mod logger;
+pub mod memory_area;
 pub mod memory_map;
+pub mod memory_stats;
+pub mod mmio;
+pub mod virtual_memory;
+
+pub use global_allocator::init_core_caches;
 
 use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use logger::Logger;
 use spin::Mutex;
 
+use crate::multicore::lock::CoreMutex;
+
 lazy_static! {
     pub static ref GLOBAL_LOGGER: Mutex<Box<dyn Logger>> =
         Mutex::new(Box::new(<dyn Logger>::new()));
@@ -16,7 +29,7 @@ lazy_static! {
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         lazy_static! {
-            pub static ref KERNEL_MEMORY_MAP: Mutex<crate::arch::x86_64::paging::X86MemoryMap<x86_64::structures::paging::OffsetPageTable<'static>>> = Mutex::new(unsafe { crate::arch::x86_64::paging::X86MemoryMap::current_memory_map() });
+            pub static ref KERNEL_MEMORY_MAP: CoreMutex<crate::arch::x86_64::paging::X86MemoryMap<x86_64::structures::paging::OffsetPageTable<'static>>> = CoreMutex::new(unsafe { crate::arch::x86_64::paging::X86MemoryMap::current_memory_map() });
         }
     } else {
         compile_error!("Kernel Memory Map for the current architecture is not implemented yet");
@@ -0,0 +1,43 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How often the timer ticks unless the kernel asks for something else
+pub const DEFAULT_TICK_FREQUENCY: u64 = 1000;
+pub const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Ticks since the timer started
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Ticks per second, 0 until the timer started
+static TICK_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer ticks since boot, only ever goes up
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Ticks per second, 0 if the periodic timer isn't running
+pub fn tick_frequency() -> u64 {
+    TICK_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since the clock was calibrated, only ever goes up
+///
+/// More precise than the ticks and keeps counting when the timer is in one-shot mode.
+pub fn nanoseconds() -> u64 {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            crate::arch::x86_64::timer::nanoseconds()
+        } else {
+            todo!()
+        }
+    }
+}
+
+/// Called by the architecture code when the periodic timer starts ticking `frequency` times per second
+pub fn set_tick_frequency(frequency: u64) {
+    TICK_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Called by the architecture code on every tick of the periodic timer
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,80 @@
+use core::ptr::null_mut;
+
+use spin::Mutex;
+
+use super::slab::{SlabAllocator, SIZE_CLASSES};
+
+/// Number of objects a magazine can hold
+pub const MAGAZINE_CAPACITY: usize = 32;
+
+/// A stack of free objects of a single size class
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_CAPACITY],
+    count: usize,
+}
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [null_mut(); MAGAZINE_CAPACITY],
+            count: 0,
+        }
+    }
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.count = self.count.checked_sub(1)?;
+        Some(self.objects[self.count])
+    }
+    fn push(&mut self, object: *mut u8) -> bool {
+        if self.count == MAGAZINE_CAPACITY {
+            return false;
+        }
+        self.objects[self.count] = object;
+        self.count += 1;
+        true
+    }
+}
+
+/// Small objects kept by a single core, so most allocations and frees never lock the slab allocator
+///
+/// Magazines are refilled from the slabs and flushed back to them half a magazine at a time.
+pub struct CoreCache {
+    magazines: [Magazine; SIZE_CLASSES.len()],
+}
+impl CoreCache {
+    pub const fn new() -> Self {
+        Self {
+            magazines: [const { Magazine::new() }; SIZE_CLASSES.len()],
+        }
+    }
+    pub fn allocate(&mut self, class: usize, slab: &Mutex<SlabAllocator>) -> *mut u8 {
+        let magazine = &mut self.magazines[class];
+        if let Some(object) = magazine.pop() {
+            return object;
+        }
+        let mut slab = slab.lock();
+        for _ in 0..MAGAZINE_CAPACITY / 2 {
+            let object = slab.allocate_object(class);
+            if object.is_null() {
+                break;
+            }
+            magazine.push(object);
+        }
+        magazine.pop().unwrap_or(null_mut())
+    }
+    /// # Safety
+    /// `ptr` must have been allocated from the size class with index `class`
+    pub unsafe fn deallocate(&mut self, class: usize, ptr: *mut u8, slab: &Mutex<SlabAllocator>) {
+        let magazine = &mut self.magazines[class];
+        if !magazine.push(ptr) {
+            let mut slab = slab.lock();
+            while magazine.count > MAGAZINE_CAPACITY / 2 {
+                slab.deallocate_object(class, magazine.pop().unwrap());
+            }
+            magazine.push(ptr);
+        }
+    }
+}
+impl Default for CoreCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
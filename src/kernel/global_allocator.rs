@@ -1,34 +1,157 @@
 use crate::kernel::KERNEL_MEMORY_MAP;
+use crate::multicore::local::CoreLocal;
+use crate::multicore::lock::{CoreMutex, CoreMutexGuard};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ops::DerefMut;
+use core::ops::Range;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+use super::core_cache::CoreCache;
 use super::heap::KernelHeap;
+use super::memory_area::KERNEL_MEMORY_AREAS;
+use super::memory_map::{MemoryFlags, MemoryMap, PageSize};
+use super::memory_stats::HEAP_PAGES;
+use super::slab::SlabAllocator;
+use super::virtual_memory;
 struct KernelHeapAllocator;
 
+/// Forwards to [`KERNEL_MEMORY_MAP`], locking it only for the duration of each call
+///
+/// The heap touches its demand paged memory between calls, and the page fault handler needs the lock to map it.
+struct LockingKernelMemoryMap;
+unsafe impl MemoryMap for LockingKernelMemoryMap {
+    unsafe fn map_page(
+        &mut self,
+        from: usize,
+        to: usize,
+        size: PageSize,
+        flags: MemoryFlags,
+    ) -> bool {
+        KERNEL_MEMORY_MAP.lock().map_page(from, to, size, flags)
+    }
+    fn page_size(&self, from: usize) -> Option<PageSize> {
+        KERNEL_MEMORY_MAP.lock().page_size(from)
+    }
+    unsafe fn unmap_memory(&mut self, from: usize) -> Option<usize> {
+        KERNEL_MEMORY_MAP.lock().unmap_memory(from)
+    }
+    unsafe fn load_memory_map(&self) {
+        KERNEL_MEMORY_MAP.lock().load_memory_map()
+    }
+    fn translate(&self, from: usize) -> Option<(usize, MemoryFlags)> {
+        KERNEL_MEMORY_MAP.lock().translate(from)
+    }
+    unsafe fn protect(&mut self, range: Range<usize>, flags: MemoryFlags) -> bool {
+        KERNEL_MEMORY_MAP.lock().protect(range, flags)
+    }
+    unsafe fn remap_page(&mut self, from: usize, to: usize, flags: MemoryFlags) -> Option<usize> {
+        KERNEL_MEMORY_MAP.lock().remap_page(from, to, flags)
+    }
+}
+
+/// Locks [`GLOBAL_KERNEL_HEAP`], the current core must not hold the locks its page faults need
+fn lock_heap() -> CoreMutexGuard<'static, KernelHeap> {
+    // A fault on a heap page would be reported as a bug, and another core waiting for the
+    // locks while holding the heap would never get them
+    debug_assert!(
+        !KERNEL_MEMORY_MAP.is_held_by_current_core()
+            && !KERNEL_MEMORY_AREAS.is_held_by_current_core(),
+        "The kernel heap can't be used while holding the kernel memory map or areas"
+    );
+    GLOBAL_KERNEL_HEAP.lock()
+}
+
+/// Runs `f` with the allocator cache of the current core, returns None if the cache isn't available
+fn with_core_cache<R>(f: impl FnOnce(&mut CoreCache) -> R) -> Option<R> {
+    let run = || CORE_CACHES.try_write().map(|mut cache| f(&mut cache));
+    // An interrupt handler allocating on this core must not find the cache half updated
+    #[cfg(target_arch = "x86_64")]
+    return x86_64::instructions::interrupts::without_interrupts(run);
+    #[cfg(not(target_arch = "x86_64"))]
+    run()
+}
+
+/// Slab size class used for `layout`, None if it should go to the heap
+///
+/// With `heap-debug` everything goes to the heap so every allocation gets red zones.
+fn size_class(layout: Layout) -> Option<usize> {
+    if cfg!(feature = "heap-debug") {
+        return None;
+    }
+    SlabAllocator::size_class(layout)
+}
+
+/// Creates the allocator caches of every core, small allocations only use the global slabs before this
+pub fn init_core_caches() {
+    CORE_CACHES.init();
+}
+
 unsafe impl GlobalAlloc for KernelHeapAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let p = GLOBAL_KERNEL_HEAP
-            .lock()
-            .allocate(layout, KERNEL_MEMORY_MAP.lock().deref_mut());
+        if let Some(class) = size_class(layout) {
+            return with_core_cache(|cache| cache.allocate(class, &GLOBAL_SLAB_ALLOCATOR))
+                .unwrap_or_else(|| GLOBAL_SLAB_ALLOCATOR.lock().allocate_object(class));
+        }
+        let p = lock_heap().allocate(layout, &mut LockingKernelMemoryMap);
         assert!(p.is_aligned());
         p
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        GLOBAL_KERNEL_HEAP.lock().deallocate(ptr)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = size_class(layout) {
+            if with_core_cache(|cache| cache.deallocate(class, ptr, &GLOBAL_SLAB_ALLOCATOR))
+                .is_none()
+            {
+                GLOBAL_SLAB_ALLOCATOR.lock().deallocate_object(class, ptr);
+            }
+            return;
+        }
+        let mut heap = lock_heap();
+        heap.deallocate(ptr);
+        // Memory taken by a burst of allocations is given back once it is freed
+        if heap.should_shrink() {
+            heap.shrink_heap(&mut LockingKernelMemoryMap);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (size_class(layout), size_class(new_layout)) {
+            (Some(class), Some(new_class)) if class == new_class => return ptr,
+            (None, None) => {
+                if lock_heap().reallocate(ptr, new_layout, &mut LockingKernelMemoryMap) {
+                    return ptr;
+                }
+            }
+            _ => {}
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: KernelHeapAllocator = KernelHeapAllocator;
-const KERNEL_HEAP_START_ADDRESS: usize = 1024 * 1024 * 1024 * 10;
 const KERNEL_HEAP_INITIAL_SIZE: usize = 1024 * 1024;
 const KERNEL_HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024 * 4;
+/// Small allocations are served by the slabs, everything else goes to [`GLOBAL_KERNEL_HEAP`]
+static GLOBAL_SLAB_ALLOCATOR: Mutex<SlabAllocator> = Mutex::new(SlabAllocator::new());
+/// Magazines of small objects for every core, in front of [`GLOBAL_SLAB_ALLOCATOR`]
+static CORE_CACHES: CoreLocal<CoreCache> = CoreLocal::new(CoreCache::new);
 lazy_static! {
-    static ref GLOBAL_KERNEL_HEAP: Mutex<KernelHeap> = unsafe {
-        Mutex::new(KernelHeap::init(KERNEL_HEAP_START_ADDRESS, KERNEL_HEAP_MAX_SIZE, KERNEL_HEAP_INITIAL_SIZE, KERNEL_MEMORY_MAP.lock().deref_mut())
+    static ref GLOBAL_KERNEL_HEAP: CoreMutex<KernelHeap> = unsafe {
+        // The heap keeps its virtual region forever, only the pages it touches take memory
+        let region = virtual_memory::reserve_randomized(KERNEL_HEAP_MAX_SIZE, PageSize::Size1GiB.size())
+            .expect("Failed to reserve the heap virtual region");
+        assert!(
+            KERNEL_MEMORY_AREAS.lock().insert(region.start()..region.end(), MemoryFlags::KERNEL_DATA, Some(&HEAP_PAGES)),
+            "Failed to add the heap memory area"
+        );
+        CoreMutex::new(KernelHeap::init_demand_paged(region.start(), KERNEL_HEAP_MAX_SIZE, KERNEL_HEAP_INITIAL_SIZE)
                 .expect("Failed to initialize heap"))
     };
 }
@@ -102,6 +225,37 @@ mod tests {
             assert_eq!(v[0], 42);
         }
     }
+    #[test(name = "Grow a vector one element at a time past the slab sizes")]
+    fn grow_vector_past_slab_sizes() {
+        let mut test_vec: Vec<u32> = Vec::new();
+        for i in 0..10_000 {
+            test_vec.push(i);
+        }
+        assert!(test_vec.iter().enumerate().all(|(i, &v)| v == i as u32));
+        test_vec.truncate(100);
+        test_vec.shrink_to_fit();
+        assert!(test_vec.iter().enumerate().all(|(i, &v)| v == i as u32));
+    }
+    #[test(name = "Grow vectors that are interleaved with other allocations")]
+    fn grow_interleaved_vectors() {
+        let mut first: Vec<u8> = Vec::with_capacity(4096);
+        let mut second: Vec<u8> = Vec::with_capacity(4096);
+        for i in 0..20_000 {
+            first.push(i as u8);
+            second.push(!(i as u8));
+        }
+        assert!(first.iter().enumerate().all(|(i, &v)| v == i as u8));
+        assert!(second.iter().enumerate().all(|(i, &v)| v == !(i as u8)));
+    }
+    #[test(name = "Small allocations are served by the core cache without locking the slabs")]
+    fn small_allocations_skip_the_slab_lock() {
+        // Make sure the cache of this core has objects of this size class
+        drop(Box::new(0u64));
+        let _slab = super::GLOBAL_SLAB_ALLOCATOR.lock();
+        let boxed = Box::new(42u64);
+        assert_eq!(*boxed, 42);
+        drop(boxed);
+    }
     #[test(name = "Try concatenating strings")]
     fn concat_strings() {
         // Testa concatenar strings
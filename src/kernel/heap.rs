@@ -1,16 +1,42 @@
 use core::{
     alloc::Layout,
     ptr::{null_mut, NonNull},
+    sync::atomic::Ordering,
 };
 
+use super::memory_map::{MemoryFlags, MemoryMap, PageSize};
+use super::memory_stats::HEAP_PAGES;
 use crate::bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE};
-use super::memory_map::{MemoryFlags, MemoryMap};
 
+/// Written in the header of every node to detect double frees and corrupted headers
+#[cfg(feature = "heap-debug")]
+const NODE_MAGIC_FREE: u32 = 0xF8EE_F8EE;
+#[cfg(feature = "heap-debug")]
+const NODE_MAGIC_IN_USE: u32 = 0xA110_CA7E;
+/// Freed memory is filled with this byte so use after free bugs are easy to spot
+#[cfg(feature = "heap-debug")]
+pub const POISON_BYTE: u8 = 0xDE;
+/// Bytes placed before and after every allocation, checked when it is freed
+#[cfg(feature = "heap-debug")]
+const RED_ZONE_BYTE: u8 = 0xCA;
+#[cfg(feature = "heap-debug")]
+const RED_ZONE_SIZE: usize = 16;
+/// Size of the pages used when the heap grows by a lot at once
+const HUGE_PAGE: PageSize = PageSize::Size2MiB;
+/// The heap is only shrunk once this many pages at its end are free, so it doesn't keep mapping and unmapping the same pages
+const SHRINK_THRESHOLD_PAGES: usize = 16;
+
+#[repr(align(16))]
 struct Node {
     length: usize,
     last: Option<NonNull<Self>>,
     next: Option<NonNull<Self>>,
     in_use: bool,
+    #[cfg(feature = "heap-debug")]
+    magic: u32,
+    /// Size the allocation was requested with, needed to find the red zone after it
+    #[cfg(feature = "heap-debug")]
+    requested_size: usize,
 }
 impl Node {
     unsafe fn split(&mut self, first_half_length: usize) {
@@ -25,6 +51,10 @@ impl Node {
             in_use: false,
             last: NonNull::new(self),
             next: self.next,
+            #[cfg(feature = "heap-debug")]
+            magic: NODE_MAGIC_FREE,
+            #[cfg(feature = "heap-debug")]
+            requested_size: 0,
         };
         let next_node_ptr = self.data_pointer::<Node>().byte_add(first_half_length);
         *next_node_ptr = next_node;
@@ -39,6 +69,11 @@ impl Node {
         let next = self.next.expect("next node must not be null").as_mut();
         self.length += next.length + size_of::<Self>();
         self.next = next.next;
+        #[cfg(feature = "heap-debug")]
+        {
+            // The header is part of this node's data now
+            next.magic = 0;
+        }
         let Some(new_next) = self.next.map(|mut n| n.as_mut()) else {
             return;
         };
@@ -46,12 +81,20 @@ impl Node {
     }
     fn set_in_use(&mut self, in_use: bool) {
         self.in_use = in_use;
+        #[cfg(feature = "heap-debug")]
+        {
+            self.magic = if in_use {
+                NODE_MAGIC_IN_USE
+            } else {
+                NODE_MAGIC_FREE
+            };
+        }
     }
     fn is_in_use(&self) -> bool {
         self.in_use
     }
-    fn data_address(&mut self) -> usize {
-        ((self as *mut Self) as usize) + size_of::<Self>()
+    fn data_address(&self) -> usize {
+        ((self as *const Self) as usize) + size_of::<Self>()
     }
     fn data_pointer<T>(&mut self) -> *mut T {
         self.data_address() as *mut T
@@ -64,14 +107,48 @@ pub struct KernelHeap {
     start: usize,
     current_size: usize,
     max_size: usize,
+    /// The heap never shrinks below its initial size
+    min_size: usize,
     last_node: NonNull<Node>,
+    /// Pages are mapped by the page fault handler when they are first touched instead of when the heap grows
+    demand_paged: bool,
 }
 impl KernelHeap {
+    #[allow(dead_code)]
     pub unsafe fn init(
         start: usize,
         max_size: usize,
         initial_size: usize,
         mapper: &mut dyn MemoryMap,
+    ) -> Option<Self> {
+        let initial_size_pages = initial_size.div_ceil(PAGE_SIZE);
+        // The heap is required to have a contiguous memory region
+        // The page allocator however does not guarantee that the allocated pages are one after the other in memory
+        // This allocates the necessary number of pages and maps them to a contiguous virtual memory region
+        for current_page in 0..initial_size_pages {
+            let page = GLOBAL_PAGE_ALLOCATOR.lock().request_page()?.into();
+            mapper.map_memory(start + (current_page * PAGE_SIZE), page, MemoryFlags::default());
+            HEAP_PAGES.fetch_add(1, Ordering::Relaxed);
+        }
+        Self::init_nodes(start, max_size, initial_size, false)
+    }
+    /// Creates a heap in a demand paged memory area, no memory is used until the heap is touched
+    ///
+    /// # Safety
+    /// `start..start + max_size` must be covered by a writable demand paged area that only this heap uses
+    pub unsafe fn init_demand_paged(
+        start: usize,
+        max_size: usize,
+        initial_size: usize,
+    ) -> Option<Self> {
+        Self::init_nodes(start, max_size, initial_size, true)
+    }
+    /// Writes the root node, which takes the whole initial size
+    unsafe fn init_nodes(
+        start: usize,
+        max_size: usize,
+        initial_size: usize,
+        demand_paged: bool,
     ) -> Option<Self> {
         assert!(
             initial_size <= max_size,
@@ -81,41 +158,63 @@ impl KernelHeap {
             initial_size > size_of::<Node>(),
             "Initial heap size must be able to fit at least 1 node inside"
         );
-
         let initial_size_pages = initial_size.div_ceil(PAGE_SIZE);
-        // The heap is required to have a contiguous memory region
-        // The page allocator however does not guarantee that the allocated pages are one after the other in memory
-        // This allocates the necessary number of pages and maps them to a contiguous virtual memory region
-        for current_page in 0..initial_size_pages {
-            let page = GLOBAL_PAGE_ALLOCATOR.lock().request_page()?.into();
-            mapper.map_memory(start + (current_page * PAGE_SIZE), page, MemoryFlags::default());
-        }
         (*(start as *mut Node)) = Node {
             in_use: false,
             last: None,
             next: None,
             length: initial_size - size_of::<Node>(),
+            #[cfg(feature = "heap-debug")]
+            magic: NODE_MAGIC_FREE,
+            #[cfg(feature = "heap-debug")]
+            requested_size: 0,
         };
         Some(Self {
             start,
             current_size: initial_size_pages,
+            min_size: initial_size_pages,
             max_size: max_size.div_floor(PAGE_SIZE),
             last_node: NonNull::new(start as *mut Node)?,
+            demand_paged,
         })
     }
 
     pub fn expand_heap(&mut self, amount: usize, mapper: &mut dyn MemoryMap) -> bool {
-        let amount = amount.next_power_of_two().max(2usize.pow(4));
+        // A new node might be created at the end, so there must be room for at least its header
+        let amount = amount.next_power_of_two().max(2 * size_of::<Node>());
         let new_size = self.current_size + amount.div_ceil(PAGE_SIZE);
         if new_size >= self.max_size {
             return false;
         }
-        for current_page in self.current_size..new_size {
-            let Some(physical_page_address) = GLOBAL_PAGE_ALLOCATOR.lock().request_page() else {
-                return false;
-            };
+        // Demand paged heaps have nothing to map, their new pages are faulted in
+        let mut current_page = if self.demand_paged {
+            new_size
+        } else {
+            self.current_size
+        };
+        while current_page < new_size {
             let virtual_page_address = self.start + (current_page * PAGE_SIZE);
-            unsafe { mapper.map_memory(virtual_page_address, physical_page_address.into(), MemoryFlags::default()) };
+            let pages = if self.map_huge_page(virtual_page_address, new_size - current_page, mapper)
+            {
+                HUGE_PAGE.size() / PAGE_SIZE
+            } else {
+                let Some(physical_page_address) = GLOBAL_PAGE_ALLOCATOR.lock().request_page()
+                else {
+                    // The pages mapped so far are still part of the heap, the next expansion uses them
+                    self.current_size = current_page;
+                    return false;
+                };
+                unsafe {
+                    mapper.map_memory(
+                        virtual_page_address,
+                        physical_page_address.into(),
+                        MemoryFlags::default(),
+                    )
+                };
+                1
+            };
+            HEAP_PAGES.fetch_add(pages, Ordering::Relaxed);
+            current_page += pages;
         }
         self.current_size = new_size;
         unsafe {
@@ -127,24 +226,138 @@ impl KernelHeap {
                     in_use: false,
                     last: Some(NonNull::from(&mut *last_node)),
                     next: None,
+                    #[cfg(feature = "heap-debug")]
+                    magic: NODE_MAGIC_FREE,
+                    #[cfg(feature = "heap-debug")]
+                    requested_size: 0,
                 };
                 last_node.next = NonNull::new(new_node_ptr);
+                self.last_node = NonNull::new(new_node_ptr).unwrap();
             } else {
                 last_node.length += amount;
             }
         }
         true
     }
-    fn root_node(&mut self) -> *mut Node {
+    /// Pages at the end of the heap that are mapped but not used by any node
+    fn trailing_free_pages(&self) -> usize {
+        let last_node = unsafe { self.last_node.as_ref() };
+        if last_node.is_in_use() {
+            return 0;
+        }
+        // The last node keeps at least the smallest data length
+        let used_end = (last_node.data_address() + 16).next_multiple_of(PAGE_SIZE);
+        let used_pages = ((used_end - self.start) / PAGE_SIZE).max(self.min_size);
+        self.current_size.saturating_sub(used_pages)
+    }
+    /// Whether enough pages at the end of the heap are free for [`KernelHeap::shrink_heap`] to be worth it
+    pub fn should_shrink(&self) -> bool {
+        self.trailing_free_pages() >= SHRINK_THRESHOLD_PAGES
+    }
+    /// Unmaps the free pages at the end of the heap and gives them back to the page allocator,
+    /// returns how many pages were released
+    pub fn shrink_heap(&mut self, mapper: &mut dyn MemoryMap) -> usize {
+        let target_end = self.start + (self.current_size - self.trailing_free_pages()) * PAGE_SIZE;
+        let mut end = self.start + (self.current_size * PAGE_SIZE);
+        while end > target_end {
+            let size = mapper
+                .page_size(end - PAGE_SIZE)
+                .unwrap_or(PageSize::Size4KiB)
+                .size();
+            let page = end - size;
+            if page < target_end {
+                // Part of this huge page is still in use
+                break;
+            }
+            // The pages are only used by the free last node, nothing points into them
+            unsafe { self.unmap_heap_page(page, mapper) };
+            end = page;
+        }
+        let released_pages = self.current_size - (end - self.start) / PAGE_SIZE;
+        if released_pages == 0 {
+            return 0;
+        }
+        self.current_size -= released_pages;
+        let last_node = unsafe { self.last_node.as_mut() };
+        last_node.length = end - last_node.data_address();
+        released_pages
+    }
+    /// Maps a huge page at `address` if it is aligned and the heap grows by at least `pages` pages
+    fn map_huge_page(&self, address: usize, pages: usize, mapper: &mut dyn MemoryMap) -> bool {
+        if address % HUGE_PAGE.size() != 0 || pages * PAGE_SIZE < HUGE_PAGE.size() {
+            return false;
+        }
+        let Some(frames) = GLOBAL_PAGE_ALLOCATOR.lock().request_pages(
+            HUGE_PAGE.size() / PAGE_SIZE,
+            HUGE_PAGE.size(),
+            usize::MAX,
+        ) else {
+            return false;
+        };
+        if unsafe { mapper.map_page(address, frames.get(), HUGE_PAGE, MemoryFlags::default()) } {
+            return true;
+        }
+        GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .free_contiguous_pages(frames, HUGE_PAGE.size() / PAGE_SIZE);
+        false
+    }
+    /// Unmaps the heap page at `address` and frees its frames, returns its size
+    unsafe fn unmap_heap_page(&self, address: usize, mapper: &mut dyn MemoryMap) -> usize {
+        let size = mapper
+            .page_size(address)
+            .unwrap_or(PageSize::Size4KiB)
+            .size();
+        if let Some(physical_page_address) = mapper.unmap_memory(address) {
+            GLOBAL_PAGE_ALLOCATOR
+                .lock()
+                .free_pages(physical_page_address, size);
+            HEAP_PAGES.fetch_sub(size / PAGE_SIZE, Ordering::Relaxed);
+        }
+        size
+    }
+    /// Unmaps every page of the heap and gives them back to the page allocator
+    ///
+    /// # Safety
+    /// Nothing allocated from this heap may be used afterwards
+    #[allow(dead_code)]
+    pub unsafe fn destroy(self, mapper: &mut dyn MemoryMap) {
+        let end = self.start + (self.current_size * PAGE_SIZE);
+        let mut address = self.start;
+        while address < end {
+            address += self.unmap_heap_page(address, mapper);
+        }
+    }
+    fn root_node(&self) -> *mut Node {
         self.start as *mut Node
     }
-    pub fn allocate(&mut self, layout: Layout, mapper: &mut dyn MemoryMap) -> *mut u8 {
+    /// Length of the node data needed to fit `layout`
+    fn node_length(layout: Layout) -> usize {
         let layout = layout.align_to(16).unwrap().pad_to_align();
-        let layout = Layout::from_size_align(
-            layout.size().next_power_of_two().max(2usize.pow(4)),
-            layout.align(),
-        )
-        .unwrap();
+        layout.size().next_power_of_two().max(2usize.pow(4))
+    }
+    pub fn allocate(&mut self, layout: Layout, mapper: &mut dyn MemoryMap) -> *mut u8 {
+        #[cfg(feature = "heap-debug")]
+        {
+            let padded_layout =
+                Layout::from_size_align(layout.size() + 2 * RED_ZONE_SIZE, layout.align()).unwrap();
+            let data = self.allocate_node(padded_layout, mapper);
+            if data.is_null() {
+                return data;
+            }
+            unsafe {
+                (*Node::from_data_pointer(data)).requested_size = layout.size();
+                data.write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+                data.add(RED_ZONE_SIZE + layout.size())
+                    .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+                data.add(RED_ZONE_SIZE)
+            }
+        }
+        #[cfg(not(feature = "heap-debug"))]
+        self.allocate_node(layout, mapper)
+    }
+    fn allocate_node(&mut self, layout: Layout, mapper: &mut dyn MemoryMap) -> *mut u8 {
+        let layout = Layout::from_size_align(Self::node_length(layout), layout.align()).unwrap();
         let Some(mut current_node) =
             NonNull::new(self.root_node()).map(|mut r| unsafe { r.as_mut() })
         else {
@@ -185,7 +398,97 @@ impl KernelHeap {
             }
         }
     }
+    /// Resizes the allocation at `address` without moving it, returns false if it has to be moved
+    ///
+    /// Growing absorbs the free nodes right after the allocation, expanding the heap when it is the
+    /// last node, and shrinking splits off the end of the node.
+    ///
+    /// # Safety
+    /// `address` must have been returned by [`KernelHeap::allocate`] and not deallocated yet
+    pub unsafe fn reallocate(
+        &mut self,
+        address: *mut u8,
+        new_layout: Layout,
+        mapper: &mut dyn MemoryMap,
+    ) -> bool {
+        // Moving the allocation every time also checks its red zones
+        if cfg!(feature = "heap-debug") {
+            return false;
+        }
+        let new_length = Self::node_length(new_layout);
+        let node = &mut *Node::from_data_pointer(address);
+        while node.length < new_length {
+            match node.next {
+                Some(next) if !next.as_ref().is_in_use() => {
+                    node.set_in_use(false);
+                    node.combine_forward();
+                    node.set_in_use(true);
+                    if self.last_node == next {
+                        self.last_node = NonNull::from(&mut *node);
+                    }
+                }
+                Some(_) => return false,
+                None => {
+                    if !self.expand_heap(new_length - node.length, mapper) {
+                        return false;
+                    }
+                }
+            }
+        }
+        self.trim_node(node, new_length);
+        true
+    }
+    /// Gives the end of an allocated node back to the heap when it can fit another node
+    unsafe fn trim_node(&mut self, node: &mut Node, length: usize) {
+        if node.length < length + size_of::<Node>() + 2usize.pow(4) {
+            return;
+        }
+        node.set_in_use(false);
+        node.split(length);
+        node.set_in_use(true);
+        let mut tail = node.next.expect("split node must have a next node");
+        let tail = tail.as_mut();
+        if let Some(next) = tail.next.filter(|next| !next.as_ref().is_in_use()) {
+            tail.combine_forward();
+            if self.last_node == next {
+                self.last_node = NonNull::from(&mut *tail);
+            }
+        }
+        if tail.next.is_none() {
+            self.last_node = NonNull::from(tail);
+        }
+    }
+    /// Checks the header and red zones of the allocation at `address`
+    ///
+    /// # Safety
+    /// `address` must have been returned by [`KernelHeap::allocate`]
+    #[cfg(feature = "heap-debug")]
+    pub unsafe fn red_zones_intact(&self, address: *mut u8) -> bool {
+        let data = address.sub(RED_ZONE_SIZE);
+        let node = &*Node::from_data_pointer(data);
+        let front = core::slice::from_raw_parts(data, RED_ZONE_SIZE);
+        let back = core::slice::from_raw_parts(address.add(node.requested_size), RED_ZONE_SIZE);
+        front.iter().chain(back).all(|&b| b == RED_ZONE_BYTE)
+    }
     pub unsafe fn deallocate(&mut self, address: *mut u8) {
+        #[cfg(feature = "heap-debug")]
+        let address = {
+            let data = address.sub(RED_ZONE_SIZE);
+            let node = &mut *Node::from_data_pointer(data);
+            assert!(
+                node.magic == NODE_MAGIC_IN_USE,
+                "Double free or invalid pointer: tried to free 0x{:X}",
+                address as usize
+            );
+            assert!(
+                self.red_zones_intact(address),
+                "Heap buffer overflow: the red zones around 0x{:X} ({} bytes) were overwritten",
+                address as usize,
+                node.requested_size
+            );
+            data.write_bytes(POISON_BYTE, node.length);
+            data
+        };
         let mut node = Node::from_data_pointer(address);
         (*node).set_in_use(false);
         if let Some(mut last) = (*node).last.filter(|last| !last.as_ref().is_in_use()) {
@@ -199,6 +502,51 @@ impl KernelHeap {
             self.last_node = NonNull::new(node).unwrap();
         }
     }
+
+    /// Walks the whole node list checking that it is consistent, panics if it isn't
+    #[allow(dead_code)]
+    pub fn verify(&self) {
+        let heap_end = self.start + (self.current_size * PAGE_SIZE);
+        let mut previous = None;
+        let mut current = NonNull::new(self.root_node());
+        while let Some(node_pointer) = current {
+            let address = node_pointer.as_ptr() as usize;
+            let node = unsafe { node_pointer.as_ref() };
+            assert!(
+                node.last == previous,
+                "Node at 0x{address:X} doesn't point back to the node before it"
+            );
+            #[cfg(feature = "heap-debug")]
+            {
+                let expected_magic = if node.in_use {
+                    NODE_MAGIC_IN_USE
+                } else {
+                    NODE_MAGIC_FREE
+                };
+                assert!(
+                    node.magic == expected_magic,
+                    "Node at 0x{address:X} has a corrupted header"
+                );
+            }
+            let end = address + size_of::<Node>() + node.length;
+            assert!(
+                end <= heap_end,
+                "Node at 0x{address:X} goes past the end of the heap"
+            );
+            match node.next {
+                Some(next) => assert!(
+                    next.as_ptr() as usize == end,
+                    "Node at 0x{address:X} isn't followed by its next node"
+                ),
+                None => assert!(
+                    node_pointer == self.last_node,
+                    "Node at 0x{address:X} is the last node but last_node points somewhere else"
+                ),
+            }
+            previous = current;
+            current = node.next;
+        }
+    }
 }
 impl !Sync for KernelHeap {}
 unsafe impl Send for KernelHeap {}
@@ -207,37 +555,190 @@ unsafe impl Send for KernelHeap {}
 mod tests {
     use core::ops::DerefMut;
 
-    use super::super::KERNEL_MEMORY_MAP;
+    use super::super::{
+        memory_stats::MemoryStats,
+        virtual_memory::{release, reserve_aligned},
+        KERNEL_MEMORY_MAP,
+    };
     use super::*;
 
-    #[test(name = "Allocate 100 times using the heap and deallocating everything afterwards")]
-    fn test_heap_allocation_and_deallocation() {
-        let start_address = 1024 * 1024 * 1024 * 1024; // Um endereço de memória fictício para testes
-        let max_size = 1024 * 1024; // 1 MB
-        let initial_size = 1024 * 128; // 128 KB
+    /// Runs `f` with a heap of its own, which is destroyed and whose region is released afterwards
+    ///
+    /// The region is aligned to huge pages, so growing the heap by a lot can use them.
+    fn with_test_heap<R>(
+        max_size: usize,
+        initial_size: usize,
+        f: impl FnOnce(&mut KernelHeap, &mut dyn MemoryMap) -> R,
+    ) -> R {
+        let region = reserve_aligned(max_size, HUGE_PAGE.size()).unwrap();
         let mut mapper = KERNEL_MEMORY_MAP.lock();
-        // Inicializa a heap
         let mut heap = unsafe {
-            KernelHeap::init(start_address, max_size, initial_size, mapper.deref_mut())
+            KernelHeap::init(region.start(), max_size, initial_size, mapper.deref_mut())
                 .expect("Failed to initialize heap")
         };
-        let layout = Layout::from_size_align(256, 8).expect("Invalid layout");
-        let mut allocations = [null_mut::<u8>(); 100];
-        for (i, ptr) in allocations.iter_mut().enumerate() {
-            // Testa alocação de memória
-            *ptr = heap.allocate(layout, mapper.deref_mut());
+        let result = f(&mut heap, mapper.deref_mut());
+        unsafe { heap.destroy(mapper.deref_mut()) };
+        drop(mapper);
+        release(region);
+        result
+    }
+
+    #[test(name = "Allocate 100 times using the heap and deallocating everything afterwards")]
+    fn test_heap_allocation_and_deallocation() {
+        let max_size = 1024 * 1024; // 1 MB
+        let initial_size = 1024 * 128; // 128 KB
+        let stats_before = with_test_heap(max_size, initial_size, |heap, mapper| {
+            // Taken after the initial pages were mapped
+            let stats_before = MemoryStats::snapshot();
+            let layout = Layout::from_size_align(256, 8).expect("Invalid layout");
+            let mut allocations = [null_mut::<u8>(); 100];
+            for (i, ptr) in allocations.iter_mut().enumerate() {
+                // Testa alocação de memória
+                *ptr = heap.allocate(layout, mapper);
+                assert!(
+                    !ptr.is_null(),
+                    "Allocation failed after allocating {i} times"
+                );
+            }
+            for ptr in allocations {
+                // Testa liberação de memória
+                unsafe { heap.deallocate(ptr) };
+            }
+            heap.verify();
+            // Teste de expansão da heap
+            let expansion_size = 1024 * 256; // 256 KB
+            let expanded = heap.expand_heap(expansion_size, mapper);
+            assert!(expanded, "Heap expansion failed");
+            heap.verify();
+            assert!(MemoryStats::snapshot().used_by_heap > stats_before.used_by_heap);
+            stats_before
+        });
+        let stats_after = MemoryStats::snapshot();
+        let new_page_tables = stats_after.used_by_page_tables - stats_before.used_by_page_tables;
+        assert_eq!(
+            stats_after.used_by_heap + initial_size,
+            stats_before.used_by_heap
+        );
+        assert_eq!(
+            stats_after.free + new_page_tables,
+            stats_before.free + initial_size,
+            "Heap leaked physical pages"
+        );
+    }
+
+    #[test(name = "Heap node list stays consistent through splits and merges")]
+    fn test_heap_verify() {
+        with_test_heap(1024 * 1024, 1024 * 64, |heap, mapper| {
+            let mut allocations = [null_mut::<u8>(); 64];
+            for (i, ptr) in allocations.iter_mut().enumerate() {
+                let layout = Layout::from_size_align(16 << (i % 8), 8).unwrap();
+                *ptr = heap.allocate(layout, mapper);
+                assert!(!ptr.is_null());
+                heap.verify();
+            }
+            // Free every other allocation first so frees merge both backwards and forwards
+            for ptr in allocations
+                .iter()
+                .step_by(2)
+                .chain(allocations.iter().skip(1).step_by(2))
+            {
+                unsafe { heap.deallocate(*ptr) };
+                heap.verify();
+            }
+        });
+    }
+
+    #[test(name = "Shrink the heap after a burst of allocations")]
+    fn test_heap_shrinking() {
+        const BIG: usize = 1024 * 1024 * 4;
+        with_test_heap(1024 * 1024 * 32, 1024 * 64, |heap, mapper| {
+            let heap_pages_before = HEAP_PAGES.load(Ordering::Relaxed) - heap.min_size;
+            let small = heap.allocate(Layout::from_size_align(256, 8).unwrap(), mapper);
+            let big = heap.allocate(Layout::from_size_align(BIG, 8).unwrap(), mapper);
+            assert!(!small.is_null() && !big.is_null());
+            // Growing by a lot uses huge pages once the end of the heap is aligned to them
+            assert_eq!(
+                mapper.page_size(heap.start + HUGE_PAGE.size()),
+                Some(HUGE_PAGE)
+            );
             assert!(
-                !ptr.is_null(),
-                "Allocation failed after allocating {i} times"
+                !heap.should_shrink(),
+                "The heap can't shrink while its end is in use"
             );
-        }
-        for ptr in allocations {
-            // Testa liberação de memória
-            unsafe { heap.deallocate(ptr) };
-        }
-        // Teste de expansão da heap
-        let expansion_size = 1024 * 256; // 256 KB
-        let expanded = heap.expand_heap(expansion_size, mapper.deref_mut());
-        assert!(expanded, "Heap expansion failed");
+            assert_eq!(heap.shrink_heap(mapper), 0);
+
+            unsafe { heap.deallocate(big) };
+            assert!(heap.should_shrink());
+            assert!(heap.shrink_heap(mapper) > 0);
+            heap.verify();
+            // Only the initial size is kept mapped
+            assert_eq!(heap.current_size, heap.min_size);
+            assert_eq!(
+                HEAP_PAGES.load(Ordering::Relaxed),
+                heap_pages_before + heap.min_size
+            );
+
+            // The heap grows again when needed
+            let big = heap.allocate(Layout::from_size_align(BIG, 8).unwrap(), mapper);
+            assert!(!big.is_null());
+            unsafe {
+                big.write_bytes(0x42, BIG);
+                heap.deallocate(big);
+                heap.deallocate(small);
+            }
+            heap.verify();
+        });
+    }
+
+    #[cfg(feature = "heap-debug")]
+    #[test(name = "Heap debugging poisons freed memory and checks red zones")]
+    fn test_heap_debug() {
+        with_test_heap(1024 * 1024, 1024 * 64, |heap, mapper| {
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let first = heap.allocate(layout, mapper);
+            let second = heap.allocate(layout, mapper);
+            unsafe {
+                first.write_bytes(0x42, 100);
+                assert!(heap.red_zones_intact(first));
+                // Writing one byte past the end is caught
+                first.add(100).write(0x42);
+                assert!(!heap.red_zones_intact(first));
+                first.add(100).write(RED_ZONE_BYTE);
+
+                heap.deallocate(first);
+                assert!((0..100).all(|i| *first.add(i) == POISON_BYTE));
+                heap.verify();
+                heap.deallocate(second);
+                heap.verify();
+            }
+        });
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    #[test(name = "Grow and shrink heap allocations in place")]
+    fn test_heap_reallocation_in_place() {
+        with_test_heap(1024 * 1024, 1024 * 64, |heap, mapper| {
+            let layout = |size| Layout::from_size_align(size, 8).unwrap();
+            let first = heap.allocate(layout(256), mapper);
+            let second = heap.allocate(layout(256), mapper);
+            unsafe {
+                second.write_bytes(0x42, 256);
+                // The first allocation is followed by the second one, so it can't grow
+                assert!(!heap.reallocate(first, layout(1024), mapper));
+                assert!(heap.reallocate(second, layout(4096), mapper));
+                assert!((0..256).all(|i| *second.add(i) == 0x42));
+                assert!(heap.reallocate(second, layout(64), mapper));
+                assert!((0..64).all(|i| *second.add(i) == 0x42));
+
+                heap.deallocate(second);
+                assert!(heap.reallocate(first, layout(8192), mapper));
+                // Growing past the end of the heap expands it
+                assert!(heap.reallocate(first, layout(1024 * 128), mapper));
+                first.write_bytes(0x42, 1024 * 128);
+                let third = heap.allocate(layout(256), mapper);
+                assert!(!third.is_null());
+                assert!(third as usize >= first as usize + 1024 * 128);
+            }
+        });
     }
 }
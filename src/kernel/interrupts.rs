@@ -0,0 +1,171 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::{Mutex, RwLock};
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        use x86_64::instructions::interrupts::without_interrupts;
+        /// On x86_64 an IRQ is the vector in the IDT
+        pub const IRQ_COUNT: usize = 256;
+        /// IRQs [`allocate_irq`] hands out, the others are exceptions or used by the kernel itself
+        pub const FREE_IRQS: Range<Irq> =
+            32..crate::arch::x86_64::idt::APIC_TIMER_INTERRUPT_ID as Irq;
+    } else {
+        compile_error!("Interrupts for the current architecture are not implemented yet");
+    }
+}
+
+pub type Irq = usize;
+
+/// Something run when an interrupt comes in, closures taking nothing are handlers too
+///
+/// Handlers run with interrupts disabled, the interrupt is acknowledged after all of them ran.
+pub trait InterruptHandler: Send + Sync {
+    fn handle(&self, irq: Irq);
+}
+impl<F: Fn() + Send + Sync> InterruptHandler for F {
+    fn handle(&self, _irq: Irq) {
+        self()
+    }
+}
+
+/// A registered handler, needed to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    irq: Irq,
+    id: u64,
+}
+impl HandlerId {
+    pub fn irq(&self) -> Irq {
+        self.irq
+    }
+}
+
+/// Handlers of an IRQ with the IDs they were registered with
+type Handlers = RwLock<Vec<(u64, Box<dyn InterruptHandler>)>>;
+
+/// Every handler of a line runs for every interrupt on it, in the order they were registered
+static HANDLERS: [Handlers; IRQ_COUNT] = [const { RwLock::new(Vec::new()) }; IRQ_COUNT];
+static NEXT_HANDLER_ID: AtomicU64 = AtomicU64::new(0);
+/// IRQs taken by [`allocate_irq`]
+static ALLOCATED_IRQS: Mutex<[u64; IRQ_COUNT / 64]> = Mutex::new([0; IRQ_COUNT / 64]);
+
+/// Reserves a free IRQ for a driver, returns None if all of them are taken
+pub fn allocate_irq() -> Option<Irq> {
+    let mut allocated = ALLOCATED_IRQS.lock();
+    let irq = FREE_IRQS
+        .clone()
+        .find(|&irq| allocated[irq / 64] & (1 << (irq % 64)) == 0)?;
+    allocated[irq / 64] |= 1 << (irq % 64);
+    Some(irq)
+}
+
+/// Gives back an IRQ from [`allocate_irq`], its handlers must have been unregistered
+pub fn free_irq(irq: Irq) {
+    assert!(
+        HANDLERS[irq].read().is_empty(),
+        "IRQ {irq} still has handlers"
+    );
+    let mut allocated = ALLOCATED_IRQS.lock();
+    assert!(
+        allocated[irq / 64] & (1 << (irq % 64)) != 0,
+        "IRQ {irq} isn't allocated"
+    );
+    allocated[irq / 64] &= !(1 << (irq % 64));
+}
+
+/// Runs `handler` on every interrupt of `irq`, after the handlers already registered for it
+pub fn register(irq: Irq, handler: impl InterruptHandler + 'static) -> HandlerId {
+    register_boxed(irq, Box::new(handler))
+}
+
+/// Like [`register`] for a handler that already is a trait object
+pub fn register_boxed(irq: Irq, handler: Box<dyn InterruptHandler>) -> HandlerId {
+    assert!(irq < IRQ_COUNT, "IRQ {irq} doesn't exist");
+    let id = NEXT_HANDLER_ID.fetch_add(1, Ordering::Relaxed);
+    // An interrupt on this core would wait forever for the lock
+    without_interrupts(|| HANDLERS[irq].write().push((id, handler)));
+    HandlerId { irq, id }
+}
+
+/// Removes a handler, returns false if it was already removed
+///
+/// Must not be called from a handler of the same IRQ.
+pub fn unregister(handler: HandlerId) -> bool {
+    let removed = without_interrupts(|| {
+        let mut handlers = HANDLERS[handler.irq].write();
+        let index = handlers.iter().position(|&(id, _)| id == handler.id)?;
+        Some(handlers.remove(index))
+    });
+    // Dropped outside the lock, in case the handler owns something that unregisters more handlers
+    removed.is_some()
+}
+
+/// Runs the handlers of `irq`, returns false if it has none
+///
+/// Called by the architecture code with interrupts disabled.
+pub fn dispatch(irq: Irq) -> bool {
+    let handlers = HANDLERS[irq].read();
+    for (_, handler) in handlers.iter() {
+        handler.handle(irq);
+    }
+    !handlers.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    struct Counter(AtomicUsize);
+    impl InterruptHandler for Counter {
+        fn handle(&self, _irq: Irq) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test(name = "Interrupt lines can be shared and handlers unregistered")]
+    fn test_shared_irq() {
+        let irq = allocate_irq().unwrap();
+        let other = allocate_irq().unwrap();
+        assert_ne!(irq, other);
+        assert!(FREE_IRQS.contains(&irq));
+        assert!(!dispatch(irq));
+
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let closure_counter = counter.clone();
+        let closure = register(irq, move || {
+            closure_counter.0.fetch_add(10, Ordering::Relaxed);
+        });
+        let boxed = register_boxed(irq, Box::new(Counter(AtomicUsize::new(0))));
+        let shared = register(irq, ArcHandler(counter.clone()));
+        assert!(dispatch(irq));
+        assert_eq!(counter.0.load(Ordering::Relaxed), 11);
+
+        assert!(unregister(closure));
+        assert!(!unregister(closure));
+        assert!(dispatch(irq));
+        assert_eq!(counter.0.load(Ordering::Relaxed), 12);
+        assert!(unregister(shared));
+        assert!(unregister(boxed));
+        assert!(!dispatch(irq));
+
+        free_irq(irq);
+        free_irq(other);
+        assert_eq!(allocate_irq(), Some(irq));
+        free_irq(irq);
+    }
+
+    struct ArcHandler(Arc<Counter>);
+    impl InterruptHandler for ArcHandler {
+        fn handle(&self, irq: Irq) {
+            self.0.handle(irq)
+        }
+    }
+}
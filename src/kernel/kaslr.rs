@@ -0,0 +1,38 @@
+use spin::Once;
+
+use crate::limine::KERNEL_FILE;
+
+/// Kernel command line switch that keeps the layout of the kernel virtual memory the same on every boot
+const DISABLE_SWITCH: &[u8] = b"nokaslr";
+
+static ENABLED: Once<bool> = Once::new();
+
+/// Whether the heap, MMIO mappings and kernel stacks are placed at random addresses
+///
+/// Enabled unless the kernel command line contains `nokaslr`.
+pub fn enabled() -> bool {
+    *ENABLED.call_once(|| {
+        let Some(kernel_file) = KERNEL_FILE.get_response() else {
+            return true;
+        };
+        !kernel_file
+            .file()
+            .cmdline()
+            .split(u8::is_ascii_whitespace)
+            .any(|argument| argument == DISABLE_SWITCH)
+    })
+}
+
+/// Random number used to place something in the kernel virtual memory, always 0 when KASLR is disabled
+pub fn random() -> usize {
+    if !enabled() {
+        return 0;
+    }
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            crate::arch::x86_64::random::random_u64() as usize
+        } else {
+            todo!()
+        }
+    }
+}
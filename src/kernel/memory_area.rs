@@ -0,0 +1,349 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::memory_map::{physical_to_virtual, MemoryFlags, MemoryMap};
+use crate::{
+    bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+    multicore::lock::CoreMutex,
+};
+
+/// Maximum number of areas a single address space can have
+const MAX_MEMORY_AREAS: usize = 64;
+
+/// Demand paged areas of the higher half, shared by every address space
+pub static KERNEL_MEMORY_AREAS: CoreMutex<MemoryAreas> = CoreMutex::new(MemoryAreas::new());
+
+/// Access that caused a page fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FaultAccess {
+    pub write: bool,
+    pub execute: bool,
+    /// The access came from userspace
+    pub user: bool,
+    /// The page is mapped and its flags forbid the access
+    pub present: bool,
+}
+
+/// A range of virtual memory whose pages are only backed by zeroed frames once they are touched
+///
+/// Pages of an area can be shared between address spaces with [`MemoryAreas::share`],
+/// their frames are reference counted by the page allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArea {
+    start: usize,
+    end: usize,
+    flags: MemoryFlags,
+    /// Counts the pages mapped on demand, for the memory statistics
+    page_counter: Option<&'static AtomicUsize>,
+}
+impl MemoryArea {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+    /// Flags every page of the area is mapped with
+    pub fn flags(&self) -> MemoryFlags {
+        self.flags
+    }
+    /// Whether the pages of the area can be accessed like this
+    pub fn allows(&self, access: FaultAccess) -> bool {
+        (!access.write || self.flags.contains(MemoryFlags::WRITABLE))
+            && (!access.execute || !self.flags.contains(MemoryFlags::NO_EXECUTE))
+            && (!access.user || self.flags.contains(MemoryFlags::USER_ACCESSIBLE))
+    }
+}
+
+/// Unmaps the pages of `area` that were mapped on demand and releases their frames
+unsafe fn unmap_area(area: MemoryArea, mapper: &mut dyn MemoryMap) {
+    for page in area.range().step_by(PAGE_SIZE) {
+        if let Some(frame) = mapper.unmap_memory(page) {
+            GLOBAL_PAGE_ALLOCATOR.lock().release_page(frame);
+            if let Some(counter) = area.page_counter {
+                counter.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// The demand paged areas of an address space, they never overlap
+pub struct MemoryAreas {
+    areas: [Option<MemoryArea>; MAX_MEMORY_AREAS],
+}
+impl MemoryAreas {
+    pub const fn new() -> Self {
+        Self {
+            areas: [None; MAX_MEMORY_AREAS],
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryArea> {
+        self.areas.iter().flatten()
+    }
+    /// Area containing `address`
+    pub fn find(&self, address: usize) -> Option<&MemoryArea> {
+        self.iter().find(|area| area.range().contains(&address))
+    }
+    /// Adds a page aligned area, returns false if it overlaps another one or there is no room left
+    ///
+    /// Every page mapped on demand is added to `page_counter` if there is one.
+    pub fn insert(
+        &mut self,
+        range: Range<usize>,
+        flags: MemoryFlags,
+        page_counter: Option<&'static AtomicUsize>,
+    ) -> bool {
+        assert!(
+            range.start % PAGE_SIZE == 0 && range.end % PAGE_SIZE == 0 && range.start < range.end,
+            "Memory areas must be non empty and page aligned"
+        );
+        if self
+            .iter()
+            .any(|area| area.start < range.end && range.start < area.end)
+        {
+            return false;
+        }
+        let Some(slot) = self.areas.iter_mut().find(|slot| slot.is_none()) else {
+            return false;
+        };
+        *slot = Some(MemoryArea {
+            start: range.start,
+            end: range.end,
+            flags,
+            page_counter,
+        });
+        true
+    }
+    /// Removes the area starting at `start`, unmapping and freeing the pages it mapped
+    ///
+    /// # Safety
+    /// Nothing may access the area afterwards
+    pub unsafe fn release(&mut self, start: usize, mapper: &mut dyn MemoryMap) -> bool {
+        let Some(slot) = self
+            .areas
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|area| area.start == start))
+        else {
+            return false;
+        };
+        unmap_area(slot.take().unwrap(), mapper);
+        true
+    }
+    /// Removes every area like [`MemoryAreas::release`]
+    ///
+    /// # Safety
+    /// Nothing may access the areas afterwards
+    pub unsafe fn clear(&mut self, mapper: &mut dyn MemoryMap) {
+        for slot in self.areas.iter_mut() {
+            if let Some(area) = slot.take() {
+                unmap_area(area, mapper);
+            }
+        }
+    }
+    /// Gives `target` a copy of every area and maps the pages they have in `target_mapper` too,
+    /// returns false if `target` has no room for them or runs out of memory
+    ///
+    /// Writable pages become read only [`MemoryFlags::COPY_ON_WRITE`] pages in both, so the first
+    /// write to one of them gives the writer its own copy.
+    ///
+    /// # Safety
+    /// `mapper` must be the memory map these areas belong to and `target_mapper` the one of `target`
+    pub unsafe fn share(
+        &self,
+        mapper: &mut dyn MemoryMap,
+        target: &mut MemoryAreas,
+        target_mapper: &mut dyn MemoryMap,
+    ) -> bool {
+        for area in self.iter() {
+            if !target.insert(area.range(), area.flags, area.page_counter) {
+                return false;
+            }
+            for page in area.range().step_by(PAGE_SIZE) {
+                let Some((frame, flags)) = mapper.translate(page) else {
+                    continue;
+                };
+                let shared_flags = if flags.contains(MemoryFlags::WRITABLE) {
+                    (flags - MemoryFlags::WRITABLE) | MemoryFlags::COPY_ON_WRITE
+                } else {
+                    flags
+                };
+                if shared_flags != flags && !mapper.protect(page..page + PAGE_SIZE, shared_flags) {
+                    return false;
+                }
+                GLOBAL_PAGE_ALLOCATOR.lock().add_reference(frame);
+                if !target_mapper.map_memory(page, frame, shared_flags) {
+                    GLOBAL_PAGE_ALLOCATOR.lock().release_page(frame);
+                    return false;
+                }
+                if let Some(counter) = area.page_counter {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        true
+    }
+    /// Resolves a page fault at `address` if an area allows `access` to it, returns false if it can't be resolved here
+    ///
+    /// Missing pages get a zeroed frame, and writes to [`MemoryFlags::COPY_ON_WRITE`] pages a copy of the shared one.
+    ///
+    /// # Safety
+    /// `mapper` must be the memory map these areas belong to
+    pub unsafe fn handle_page_fault(
+        &self,
+        address: usize,
+        access: FaultAccess,
+        mapper: &mut dyn MemoryMap,
+    ) -> bool {
+        let Some(area) = self.find(address) else {
+            return false;
+        };
+        if !area.allows(access) {
+            return false;
+        }
+        let page = address.div_floor(PAGE_SIZE) * PAGE_SIZE;
+        if access.present {
+            return access.write && copy_on_write(page, mapper);
+        }
+        // Another core may have mapped it in the meantime
+        if mapper.translate(page).is_some() {
+            return true;
+        }
+        let Some(frame) = GLOBAL_PAGE_ALLOCATOR.lock().request_and_clear_page() else {
+            return false;
+        };
+        if !mapper.map_memory(page, frame.get(), area.flags) {
+            GLOBAL_PAGE_ALLOCATOR
+                .lock()
+                .free_pages(frame.get(), PAGE_SIZE);
+            return false;
+        }
+        if let Some(counter) = area.page_counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+}
+/// Gives the [`MemoryFlags::COPY_ON_WRITE`] page at `page` its own writable frame, returns false if it isn't one
+///
+/// Faults of the same address space must be resolved one at a time.
+unsafe fn copy_on_write(page: usize, mapper: &mut dyn MemoryMap) -> bool {
+    let Some((frame, flags)) = mapper.translate(page) else {
+        // Unmapped in the meantime, the access faults again and finds out why
+        return true;
+    };
+    if !flags.contains(MemoryFlags::COPY_ON_WRITE) {
+        // Another core may have given it its own frame in the meantime
+        return flags.contains(MemoryFlags::WRITABLE);
+    }
+    let private_flags = (flags - MemoryFlags::COPY_ON_WRITE) | MemoryFlags::WRITABLE;
+    let mut allocator = GLOBAL_PAGE_ALLOCATOR.lock();
+    // Every other address space already made its own copy
+    if allocator.references(frame) == 1 {
+        drop(allocator);
+        return mapper.protect(page..page + PAGE_SIZE, private_flags);
+    }
+    let Some(copy) = allocator.request_page() else {
+        return false;
+    };
+    drop(allocator);
+    core::ptr::copy_nonoverlapping(
+        physical_to_virtual(frame) as *const u8,
+        physical_to_virtual(copy.get()) as *mut u8,
+        PAGE_SIZE,
+    );
+    // The page stays mapped to the shared frame until the copy replaces it
+    let Some(shared) = mapper.remap_page(page, copy.get(), private_flags) else {
+        GLOBAL_PAGE_ALLOCATOR
+            .lock()
+            .free_pages(copy.get(), PAGE_SIZE);
+        return false;
+    };
+    GLOBAL_PAGE_ALLOCATOR.lock().release_page(shared);
+    true
+}
+
+impl Default for MemoryAreas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ops::DerefMut;
+
+    use super::super::{virtual_memory, KERNEL_MEMORY_MAP};
+    use super::*;
+    use crate::arch::x86_64::idt::probe_read;
+
+    #[test(name = "Memory areas refuse overlaps and accesses their flags forbid")]
+    fn test_memory_areas() {
+        let mut areas = MemoryAreas::new();
+        assert!(areas.insert(0x1000..0x5000, MemoryFlags::USER_DATA, None));
+        assert!(!areas.insert(0x4000..0x6000, MemoryFlags::USER_DATA, None));
+        assert!(areas.insert(0x5000..0x6000, MemoryFlags::USER_CODE, None));
+        assert_eq!(areas.find(0x4FFF).unwrap().range(), 0x1000..0x5000);
+        assert!(areas.find(0x6000).is_none());
+
+        let code = areas.find(0x5000).unwrap();
+        assert!(code.allows(FaultAccess {
+            execute: true,
+            user: true,
+            ..Default::default()
+        }));
+        assert!(!code.allows(FaultAccess {
+            write: true,
+            ..Default::default()
+        }));
+        assert!(!areas.find(0x1000).unwrap().allows(FaultAccess {
+            execute: true,
+            ..Default::default()
+        }));
+    }
+
+    #[test(name = "Pages of a kernel memory area are allocated on first touch")]
+    fn test_demand_paging() {
+        static PAGES: AtomicUsize = AtomicUsize::new(0);
+        let region = virtual_memory::reserve(PAGE_SIZE * 4).unwrap();
+        let range = region.start()..region.end();
+        assert!(KERNEL_MEMORY_AREAS.lock().insert(
+            range.clone(),
+            MemoryFlags::KERNEL_DATA,
+            Some(&PAGES)
+        ));
+        assert_eq!(KERNEL_MEMORY_MAP.lock().translate(range.start), None);
+
+        let value = (range.start + PAGE_SIZE + 8) as *mut u64;
+        unsafe {
+            assert_eq!(value.read_volatile(), 0);
+            value.write_volatile(0x1234);
+            assert_eq!(value.read_volatile(), 0x1234);
+        }
+        // Only the touched page is backed by memory
+        assert!(KERNEL_MEMORY_MAP.lock().translate(range.start).is_none());
+        assert!(KERNEL_MEMORY_MAP
+            .lock()
+            .translate(range.start + PAGE_SIZE)
+            .is_some());
+        assert_eq!(PAGES.load(Ordering::Relaxed), 1);
+
+        // Resolving a fault needs the memory map, the core holding it gets the fault reported instead of hanging
+        let mapper = KERNEL_MEMORY_MAP.lock();
+        let mut byte = 0;
+        assert!(!unsafe { probe_read(range.start as *const u8, &mut byte) });
+        drop(mapper);
+        assert!(unsafe { probe_read(range.start as *const u8, &mut byte) });
+        assert_eq!(PAGES.load(Ordering::Relaxed), 2);
+
+        unsafe {
+            assert!(KERNEL_MEMORY_AREAS
+                .lock()
+                .release(range.start, KERNEL_MEMORY_MAP.lock().deref_mut()));
+        }
+        assert_eq!(PAGES.load(Ordering::Relaxed), 0);
+        assert!(KERNEL_MEMORY_MAP
+            .lock()
+            .translate(range.start + PAGE_SIZE)
+            .is_none());
+        virtual_memory::release(region);
+    }
+}
@@ -1,5 +1,9 @@
+use core::ops::Range;
+
 use bitflags::bitflags;
 
+use crate::{bitmap_allocator::PAGE_SIZE, limine::HHDM};
+
 bitflags! {
     #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
     pub struct MemoryFlags: u8 {
@@ -18,16 +22,142 @@ bitflags! {
         const NO_CACHE =        1 << 3;
         /// Forbid code execution from the mapped frames.
         const NO_EXECUTE =      1 << 4;
+        /// Writes may be buffered and combined before reaching memory, meant for framebuffers.
+        ///
+        /// Takes precedence over [`MemoryFlags::WRITE_THROUGH`] and [`MemoryFlags::NO_CACHE`].
+        const WRITE_COMBINING = 1 << 5;
+        /// The page is shared read only and gets copied on the first write, see [`crate::kernel::memory_area`].
+        ///
+        /// Only recorded in the page table, the hardware ignores it.
+        const COPY_ON_WRITE =   1 << 6;
     }
 }
+impl MemoryFlags {
+    /// Kernel data, only the kernel can read and write it
+    pub const KERNEL_DATA: Self = Self::WRITABLE.union(Self::NO_EXECUTE);
+    /// Userspace data, readable and writable from ring 3
+    pub const USER_DATA: Self = Self::WRITABLE
+        .union(Self::USER_ACCESSIBLE)
+        .union(Self::NO_EXECUTE);
+    /// Userspace code, read only and executable from ring 3
+    pub const USER_CODE: Self = Self::USER_ACCESSIBLE;
+}
 impl Default for MemoryFlags {
+    /// Kernel data, userspace mappings have to ask for [`MemoryFlags::USER_DATA`] or [`MemoryFlags::USER_CODE`]
     fn default() -> Self {
-        MemoryFlags::WRITABLE | MemoryFlags::USER_ACCESSIBLE
+        MemoryFlags::KERNEL_DATA
+    }
+}
+
+/// Sizes of the pages a [`MemoryMap`] can map
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+impl PageSize {
+    /// Every page size, from the biggest to the smallest
+    pub const ALL: [PageSize; 3] = [PageSize::Size1GiB, PageSize::Size2MiB, PageSize::Size4KiB];
+    pub const fn size(self) -> usize {
+        match self {
+            PageSize::Size4KiB => PAGE_SIZE,
+            PageSize::Size2MiB => 512 * PAGE_SIZE,
+            PageSize::Size1GiB => 512 * 512 * PAGE_SIZE,
+        }
+    }
+    /// Biggest page size up to `max` that fits at `from` and `to` without going past `end`
+    pub fn best_fit(from: usize, to: usize, end: usize, max: PageSize) -> PageSize {
+        Self::ALL
+            .into_iter()
+            .filter(|&size| size <= max)
+            .find(|size| {
+                from % size.size() == 0 && to % size.size() == 0 && from + size.size() <= end
+            })
+            .unwrap_or(PageSize::Size4KiB)
     }
 }
 
 pub unsafe trait MemoryMap: Send {
-    unsafe fn map_memory(&mut self, from: usize, to: usize, flags: MemoryFlags) -> bool;
-    unsafe fn unmap_memory(&mut self, from: usize) -> bool;
+    unsafe fn map_memory(&mut self, from: usize, to: usize, flags: MemoryFlags) -> bool {
+        self.map_page(from, to, PageSize::Size4KiB, flags)
+    }
+    /// Maps a single page of `size` bytes, `from` and `to` must be aligned to it
+    ///
+    /// # Safety
+    /// The physical memory must not be in use by anything else
+    unsafe fn map_page(
+        &mut self,
+        from: usize,
+        to: usize,
+        size: PageSize,
+        flags: MemoryFlags,
+    ) -> bool;
+    /// Size of the page `from` is mapped with
+    fn page_size(&self, from: usize) -> Option<PageSize>;
+    /// Unmaps the page containing `from`, returning the physical address the start of the page was mapped to
+    ///
+    /// The whole page is unmapped when it is a huge page, see [`MemoryMap::page_size`].
+    unsafe fn unmap_memory(&mut self, from: usize) -> Option<usize>;
     unsafe fn load_memory_map(&self);
+    /// Returns the physical address `from` is mapped to and the flags of its page
+    fn translate(&self, from: usize) -> Option<(usize, MemoryFlags)>;
+    /// Changes the flags of every page in `range`, returns false if one of them isn't mapped
+    ///
+    /// # Safety
+    /// Nothing may still rely on the old flags, like writing to a page that becomes read only
+    unsafe fn protect(&mut self, range: Range<usize>, flags: MemoryFlags) -> bool;
+    /// Points the 4 KiB page at `from` to `to` with `flags`, returning the physical address it was mapped to
+    ///
+    /// The entry is swapped in place, so the page is never unmapped in between.
+    ///
+    /// # Safety
+    /// The physical memory must not be in use by anything else, and nothing may still rely on the old frame
+    unsafe fn remap_page(&mut self, from: usize, to: usize, flags: MemoryFlags) -> Option<usize>;
+    /// Maps every page in `from` to the physically contiguous memory starting at `to`
+    ///
+    /// Pages up to `max_page_size` are used wherever both addresses are aligned to them.
+    /// If a page can't be mapped, the pages mapped before it are unmapped again.
+    ///
+    /// # Safety
+    /// The physical memory must not be in use by anything else
+    unsafe fn map_range(
+        &mut self,
+        from: Range<usize>,
+        to: usize,
+        flags: MemoryFlags,
+        max_page_size: PageSize,
+    ) -> bool {
+        let mut page = from.start;
+        while page < from.end {
+            let physical_address = to + (page - from.start);
+            let size = PageSize::best_fit(page, physical_address, from.end, max_page_size);
+            if !self.map_page(page, physical_address, size, flags) {
+                self.unmap_range(from.start..page);
+                return false;
+            }
+            page += size.size();
+        }
+        true
+    }
+    /// Unmaps every page in `range`, pages that aren't mapped are skipped
+    ///
+    /// # Safety
+    /// Nothing may access the range afterwards
+    unsafe fn unmap_range(&mut self, range: Range<usize>) {
+        let mut page = range.start;
+        while page < range.end {
+            let size = self.page_size(page).unwrap_or(PageSize::Size4KiB);
+            self.unmap_memory(page);
+            page = (page + 1).next_multiple_of(size.size());
+        }
+    }
+}
+
+/// Converts a physical address into its virtual address inside the higher half direct map set up by the bootloader
+pub fn physical_to_virtual(physical_address: usize) -> usize {
+    HHDM.get_response()
+        .expect("higher half direct map should be available")
+        .offset() as usize
+        + physical_address
 }
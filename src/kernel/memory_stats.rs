@@ -0,0 +1,77 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use limine::memory_map::EntryType;
+
+use crate::{
+    bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE},
+    limine::MEMMAP_REQ,
+};
+
+/// Number of physical pages currently mapped into kernel heaps
+pub static HEAP_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// Number of physical pages currently used as page tables
+pub static PAGE_TABLE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot of how the physical memory is being used, every value is in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Memory managed by the page allocator
+    pub total: usize,
+    /// Memory that can still be allocated
+    pub free: usize,
+    pub used_by_heap: usize,
+    pub used_by_page_tables: usize,
+    /// Memory the firmware keeps for itself (reserved, ACPI and bad memory)
+    pub reserved_by_firmware: usize,
+}
+impl MemoryStats {
+    pub fn snapshot() -> Self {
+        let (total_pages, free_pages) = {
+            let allocator = GLOBAL_PAGE_ALLOCATOR.lock();
+            (allocator.number_of_pages(), allocator.free_page_count())
+        };
+        let reserved_by_firmware = MEMMAP_REQ
+            .get_response()
+            .expect("memory map should be available")
+            .entries()
+            .iter()
+            .filter(|e| {
+                [
+                    EntryType::RESERVED,
+                    EntryType::ACPI_RECLAIMABLE,
+                    EntryType::ACPI_NVS,
+                    EntryType::BAD_MEMORY,
+                ]
+                .contains(&e.entry_type)
+            })
+            .map(|e| e.length as usize)
+            .sum();
+        Self {
+            total: total_pages * PAGE_SIZE,
+            free: free_pages * PAGE_SIZE,
+            used_by_heap: HEAP_PAGES.load(Ordering::Relaxed) * PAGE_SIZE,
+            used_by_page_tables: PAGE_TABLE_PAGES.load(Ordering::Relaxed) * PAGE_SIZE,
+            reserved_by_firmware,
+        }
+    }
+    /// Memory allocated for any purpose
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Memory: {} KiB total, {} KiB free, {} KiB heap, {} KiB page tables, {} KiB reserved by firmware",
+            self.total / 1024,
+            self.free / 1024,
+            self.used_by_heap / 1024,
+            self.used_by_page_tables / 1024,
+            self.reserved_by_firmware / 1024
+        )
+    }
+}
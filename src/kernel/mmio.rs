@@ -0,0 +1,140 @@
+use core::mem::size_of;
+
+use super::memory_map::{MemoryFlags, MemoryMap};
+use super::virtual_memory::{self, VirtualRegion};
+use crate::bitmap_allocator::PAGE_SIZE;
+
+/// Memory type used for a MMIO mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes straight to the device, for registers
+    Uncached,
+    /// Reads may be cached but every write reaches the device
+    WriteThrough,
+    /// Writes may be buffered and combined, for framebuffers
+    WriteCombining,
+}
+impl From<CacheMode> for MemoryFlags {
+    fn from(value: CacheMode) -> Self {
+        match value {
+            CacheMode::Uncached => MemoryFlags::NO_CACHE | MemoryFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => MemoryFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => MemoryFlags::WRITE_COMBINING,
+        }
+    }
+}
+
+/// A range of device memory mapped into the kernel virtual memory
+///
+/// The range stays mapped until [`IoMapping::unmap`] is called.
+pub struct IoMapping {
+    region: VirtualRegion,
+    address: usize,
+    size: usize,
+}
+impl IoMapping {
+    /// Virtual address of the first byte of the range
+    pub fn address(&self) -> usize {
+        self.address
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    fn register_address<T>(&self, offset: usize) -> usize {
+        assert!(
+            offset + size_of::<T>() <= self.size,
+            "MMIO access at offset 0x{offset:X} is outside of the mapped range"
+        );
+        let address = self.address + offset;
+        assert!(
+            address % align_of::<T>() == 0,
+            "MMIO access at offset 0x{offset:X} is misaligned"
+        );
+        address
+    }
+    /// Reads the value at `offset` bytes from the start of the range
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { (self.register_address::<T>(offset) as *const T).read_volatile() }
+    }
+    /// Writes `value` at `offset` bytes from the start of the range
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { (self.register_address::<T>(offset) as *mut T).write_volatile(value) }
+    }
+    /// Unmaps the range, the device memory itself is left untouched
+    ///
+    /// # Safety
+    /// Nothing may access the range afterwards
+    pub unsafe fn unmap(self, mapper: &mut dyn MemoryMap) {
+        virtual_memory::unmap_region(self.region, mapper);
+    }
+}
+
+/// Maps `size` bytes of device memory starting at `physical_address` into the kernel virtual memory
+///
+/// The mapping is never accessible from userspace and can't be executed.
+///
+/// # Safety
+/// The range should be device memory, RAM mapped with a different memory type than the direct map can be seen stale
+pub unsafe fn ioremap(
+    physical_address: usize,
+    size: usize,
+    cache_mode: CacheMode,
+    mapper: &mut dyn MemoryMap,
+) -> Option<IoMapping> {
+    let first_frame = physical_address.div_floor(PAGE_SIZE) * PAGE_SIZE;
+    let mapped_size = (physical_address + size).next_multiple_of(PAGE_SIZE) - first_frame;
+    let flags = MemoryFlags::WRITABLE | MemoryFlags::NO_EXECUTE | MemoryFlags::from(cache_mode);
+    // Big ranges like framebuffers get huge pages
+    let (region, address) =
+        virtual_memory::map_contiguous(first_frame, mapped_size, flags, mapper)?;
+    Some(IoMapping {
+        address: address + (physical_address - first_frame),
+        region,
+        size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        ops::DerefMut,
+        sync::atomic::{fence, Ordering},
+    };
+
+    use super::super::{memory_map::physical_to_virtual, KERNEL_MEMORY_MAP};
+    use super::*;
+    use crate::bitmap_allocator::GLOBAL_PAGE_ALLOCATOR;
+
+    #[test(name = "Access memory through uncached and write-combining MMIO mappings")]
+    fn test_ioremap() {
+        let frame = GLOBAL_PAGE_ALLOCATOR.lock().request_page().unwrap().get();
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        for cache_mode in [
+            CacheMode::Uncached,
+            CacheMode::WriteThrough,
+            CacheMode::WriteCombining,
+        ] {
+            unsafe {
+                // Not page aligned on purpose, the mapping has to start in the middle of the page
+                let mapping = ioremap(frame + 0x100, 0x20, cache_mode, mapper.deref_mut()).unwrap();
+                assert_eq!(mapping.address() % PAGE_SIZE, 0x100);
+                let (physical_address, flags) = mapper.translate(mapping.address()).unwrap();
+                assert_eq!(physical_address, frame + 0x100);
+                assert!(!flags.contains(MemoryFlags::USER_ACCESSIBLE));
+                assert!(flags.contains(MemoryFlags::from(cache_mode)));
+                mapping.write::<u32>(0x10, 0xDEADBEEF);
+                // Flushes the write-combining buffers
+                fence(Ordering::SeqCst);
+                assert_eq!(mapping.read::<u32>(0x10), 0xDEADBEEF);
+                mapping.unmap(mapper.deref_mut());
+                assert_eq!(
+                    (physical_to_virtual(frame + 0x110) as *const u32).read_volatile(),
+                    0xDEADBEEF
+                );
+                (physical_to_virtual(frame + 0x110) as *mut u32).write_volatile(0);
+            }
+        }
+        drop(mapper);
+        GLOBAL_PAGE_ALLOCATOR.lock().free_pages(frame, PAGE_SIZE);
+    }
+}
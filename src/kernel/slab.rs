@@ -0,0 +1,152 @@
+use core::{
+    alloc::Layout,
+    ptr::{null_mut, NonNull},
+    sync::atomic::Ordering,
+};
+
+use super::{memory_map::physical_to_virtual, memory_stats::HEAP_PAGES};
+use crate::bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE};
+
+/// Object sizes handed out by the slab caches, anything bigger goes to the [`super::heap::KernelHeap`]
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Free objects are linked together through their own memory
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Hands out objects of a single size carved from whole pages
+struct SlabCache {
+    object_size: usize,
+    free_list: Option<NonNull<FreeObject>>,
+}
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            free_list: None,
+        }
+    }
+    /// Takes a new page from the page allocator and splits it into objects
+    fn refill(&mut self) -> bool {
+        let Some(page) = GLOBAL_PAGE_ALLOCATOR.lock().request_page() else {
+            return false;
+        };
+        HEAP_PAGES.fetch_add(1, Ordering::Relaxed);
+        // Pages are accessed through the higher half direct map, so slabs never need new mappings
+        let page = physical_to_virtual(page.get());
+        for object in (page..page + PAGE_SIZE).step_by(self.object_size).rev() {
+            unsafe { self.push(object as *mut u8) };
+        }
+        true
+    }
+    unsafe fn push(&mut self, object: *mut u8) {
+        let object = object.cast::<FreeObject>();
+        object.write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = NonNull::new(object);
+    }
+    fn allocate(&mut self) -> *mut u8 {
+        if self.free_list.is_none() && !self.refill() {
+            return null_mut();
+        }
+        let Some(object) = self.free_list else {
+            return null_mut();
+        };
+        self.free_list = unsafe { object.as_ref() }.next;
+        object.as_ptr().cast()
+    }
+}
+
+/// Allocator for small objects with a cache for each of the [`SIZE_CLASSES`]
+///
+/// Allocating and freeing are `O(1)`, they just pop and push from the free list of the size class.
+pub struct SlabAllocator {
+    caches: [SlabCache; SIZE_CLASSES.len()],
+}
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        Self {
+            caches: [
+                SlabCache::new(SIZE_CLASSES[0]),
+                SlabCache::new(SIZE_CLASSES[1]),
+                SlabCache::new(SIZE_CLASSES[2]),
+                SlabCache::new(SIZE_CLASSES[3]),
+                SlabCache::new(SIZE_CLASSES[4]),
+                SlabCache::new(SIZE_CLASSES[5]),
+                SlabCache::new(SIZE_CLASSES[6]),
+                SlabCache::new(SIZE_CLASSES[7]),
+            ],
+        }
+    }
+    /// Index of the smallest size class that fits `layout`, or None if it is too big for the slabs
+    ///
+    /// Objects are aligned to their size, so the alignment is also taken into account.
+    pub fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+    /// Allocates an object from the size class with index `class`
+    pub fn allocate_object(&mut self, class: usize) -> *mut u8 {
+        self.caches[class].allocate()
+    }
+    /// # Safety
+    /// `ptr` must have been allocated by this allocator from the size class with index `class`
+    pub unsafe fn deallocate_object(&mut self, class: usize, ptr: *mut u8) {
+        self.caches[class].push(ptr);
+    }
+}
+impl Default for SlabAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+unsafe impl Send for SlabAllocator {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test(name = "Slab allocator picks the size class that fits size and alignment")]
+    fn test_size_classes() {
+        let class_of =
+            |size, align| SlabAllocator::size_class(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class_of(1, 1), Some(0));
+        assert_eq!(class_of(16, 8), Some(0));
+        assert_eq!(class_of(17, 8), Some(1));
+        assert_eq!(class_of(8, 64), Some(2));
+        assert_eq!(class_of(2048, 8), Some(7));
+        assert_eq!(class_of(2049, 8), None);
+        assert_eq!(class_of(8, 4096), None);
+    }
+
+    #[test(name = "Slab allocator hands out aligned objects and reuses freed ones")]
+    fn test_allocate_and_reuse() {
+        let mut slab = SlabAllocator::new();
+        for (class, &size) in SIZE_CLASSES.iter().enumerate() {
+            // More objects than fit in a single page, so the cache has to be refilled
+            let mut objects = [null_mut::<u8>(); 300];
+            for object in objects.iter_mut() {
+                *object = slab.allocate_object(class);
+                assert!(!object.is_null(), "Slab of {size} bytes ran out of memory");
+                assert_eq!(*object as usize % size, 0);
+                unsafe { object.write_bytes(0xAB, size) };
+            }
+            for (i, object) in objects.iter().enumerate() {
+                assert!(
+                    objects[i + 1..]
+                        .iter()
+                        .all(|other| (*other as usize).abs_diff(*object as usize) >= size),
+                    "Slab objects of {size} bytes overlap"
+                );
+            }
+            let freed = objects[42];
+            unsafe { slab.deallocate_object(class, freed) };
+            assert_eq!(slab.allocate_object(class), freed);
+            for object in objects {
+                unsafe { slab.deallocate_object(class, object) };
+            }
+        }
+    }
+}
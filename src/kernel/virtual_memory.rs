@@ -0,0 +1,550 @@
+use spin::{Mutex, Once};
+
+use super::{
+    kaslr,
+    memory_map::{MemoryFlags, MemoryMap, PageSize},
+};
+use crate::bitmap_allocator::{GLOBAL_PAGE_ALLOCATOR, PAGE_SIZE};
+
+/// Start of the kernel virtual address range, above the higher half direct map and below the kernel image
+pub const KERNEL_VIRTUAL_START: usize = 0xFFFF_C000_0000_0000;
+/// Size of the kernel virtual address range, 32 TiB
+pub const KERNEL_VIRTUAL_SIZE: usize = 0x2000_0000_0000;
+/// Maximum number of holes the kernel virtual address range can be split into
+const MAX_FREE_RANGES: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FreeRange {
+    start: usize,
+    pages: usize,
+}
+impl FreeRange {
+    fn end(&self) -> usize {
+        self.start + self.pages * PAGE_SIZE
+    }
+}
+
+/// A range of kernel virtual addresses reserved for a single user
+///
+/// Nothing is unmapped when it is dropped, it has to be given back with [`release`] or [`unmap_region`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct VirtualRegion {
+    start: usize,
+    pages: usize,
+}
+impl VirtualRegion {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+    pub fn end(&self) -> usize {
+        self.start + self.size()
+    }
+}
+
+/// Hands out non overlapping page aligned ranges of virtual addresses
+///
+/// Free ranges are kept in a fixed size array since the heap may itself need a range.
+pub struct VirtualAddressAllocator {
+    free_ranges: [Option<FreeRange>; MAX_FREE_RANGES],
+}
+impl VirtualAddressAllocator {
+    pub const fn new(start: usize, size: usize) -> Self {
+        let mut free_ranges = [None; MAX_FREE_RANGES];
+        free_ranges[0] = Some(FreeRange {
+            start,
+            pages: size / PAGE_SIZE,
+        });
+        Self { free_ranges }
+    }
+    /// Reserves `pages` pages from the first free range big enough
+    pub fn allocate(&mut self, pages: usize) -> Option<VirtualRegion> {
+        self.allocate_aligned(pages, PAGE_SIZE)
+    }
+    /// Reserves `pages` pages starting at a multiple of `align`, so they can be mapped with huge pages
+    pub fn allocate_aligned(&mut self, pages: usize, align: usize) -> Option<VirtualRegion> {
+        assert!(
+            align.is_power_of_two() && align >= PAGE_SIZE,
+            "Alignment must be a power of two and at least the page size"
+        );
+        if pages == 0 {
+            return None;
+        }
+        for index in 0..MAX_FREE_RANGES {
+            let Some(range) = self.free_ranges[index] else {
+                continue;
+            };
+            let start = range.start.next_multiple_of(align);
+            if start + pages * PAGE_SIZE > range.end() {
+                continue;
+            }
+            if let Some(region) = self.take(index, start, pages) {
+                return Some(region);
+            }
+        }
+        None
+    }
+    /// Reserves `pages` pages starting at a multiple of `align`, `random` picks where among every place they fit
+    pub fn allocate_randomized(
+        &mut self,
+        pages: usize,
+        align: usize,
+        random: usize,
+    ) -> Option<VirtualRegion> {
+        assert!(
+            align.is_power_of_two() && align >= PAGE_SIZE,
+            "Alignment must be a power of two and at least the page size"
+        );
+        if pages == 0 {
+            return None;
+        }
+        // Number of aligned starts the region fits at in a free range
+        let places = |range: &FreeRange| {
+            let first = range.start.next_multiple_of(align);
+            let size = pages * PAGE_SIZE;
+            if first + size > range.end() {
+                0
+            } else {
+                (range.end() - size - first) / align + 1
+            }
+        };
+        let total: usize = self.free_ranges.iter().flatten().map(places).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut place = random % total;
+        for index in 0..MAX_FREE_RANGES {
+            let Some(range) = self.free_ranges[index] else {
+                continue;
+            };
+            let count = places(&range);
+            if place >= count {
+                place -= count;
+                continue;
+            }
+            let start = range.start.next_multiple_of(align) + place * align;
+            // Splitting the range may need a slot there is no room for
+            return self
+                .take(index, start, pages)
+                .or_else(|| self.allocate_aligned(pages, align));
+        }
+        unreachable!()
+    }
+    /// Reserves `pages` pages at `start` from the free range at `index`, returns None if it has to be split
+    /// in two and there is no slot left for the second half
+    fn take(&mut self, index: usize, start: usize, pages: usize) -> Option<VirtualRegion> {
+        let range = self.free_ranges[index].unwrap();
+        let region = VirtualRegion { start, pages };
+        let before = FreeRange {
+            start: range.start,
+            pages: (region.start - range.start) / PAGE_SIZE,
+        };
+        let after = FreeRange {
+            start: region.end(),
+            pages: (range.end() - region.end()) / PAGE_SIZE,
+        };
+        match (before.pages, after.pages) {
+            (0, 0) => self.free_ranges[index] = None,
+            (0, _) => self.free_ranges[index] = Some(after),
+            (_, 0) => self.free_ranges[index] = Some(before),
+            _ => {
+                let empty_slot = self.free_ranges.iter().position(|range| range.is_none())?;
+                self.free_ranges[index] = Some(before);
+                self.free_ranges[empty_slot] = Some(after);
+            }
+        }
+        Some(region)
+    }
+    /// Gives `region` back, merging it with the free ranges around it
+    pub fn free(&mut self, region: VirtualRegion) {
+        let freed = FreeRange {
+            start: region.start,
+            pages: region.pages,
+        };
+        assert!(
+            self.free_ranges
+                .iter()
+                .flatten()
+                .all(|range| range.end() <= freed.start || range.start >= freed.end()),
+            "Virtual region 0x{:X}..0x{:X} was freed twice",
+            freed.start,
+            freed.end()
+        );
+        let before = self
+            .free_ranges
+            .iter()
+            .position(|range| range.is_some_and(|range| range.end() == freed.start));
+        let after = self
+            .free_ranges
+            .iter()
+            .position(|range| range.is_some_and(|range| range.start == freed.end()));
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                let after_pages = self.free_ranges[after].take().unwrap().pages;
+                let range = self.free_ranges[before].as_mut().unwrap();
+                range.pages += freed.pages + after_pages;
+            }
+            (Some(before), None) => self.free_ranges[before].as_mut().unwrap().pages += freed.pages,
+            (None, Some(after)) => {
+                let range = self.free_ranges[after].as_mut().unwrap();
+                range.start = freed.start;
+                range.pages += freed.pages;
+            }
+            (None, None) => {
+                let slot = self
+                    .free_ranges
+                    .iter_mut()
+                    .find(|range| range.is_none())
+                    .expect("Kernel virtual address space is too fragmented");
+                *slot = Some(freed);
+            }
+        }
+    }
+    /// Number of pages that can still be reserved
+    pub fn free_pages(&self) -> usize {
+        self.free_ranges
+            .iter()
+            .flatten()
+            .map(|range| range.pages)
+            .sum()
+    }
+}
+
+pub static KERNEL_VIRTUAL_ALLOCATOR: Mutex<VirtualAddressAllocator> = Mutex::new(
+    VirtualAddressAllocator::new(KERNEL_VIRTUAL_START, KERNEL_VIRTUAL_SIZE),
+);
+
+/// Size of [`MMIO_WINDOW`] and [`STACK_WINDOW`], 64 GiB
+const WINDOW_SIZE: usize = 0x10_0000_0000;
+
+/// Kernel virtual addresses set aside for one kind of mapping, placed at a random address
+/// when [`kaslr`] is enabled
+pub struct VirtualWindow {
+    size: usize,
+    allocator: Once<(VirtualRegion, Mutex<VirtualAddressAllocator>)>,
+}
+impl VirtualWindow {
+    pub const fn new(size: usize) -> Self {
+        Self {
+            size,
+            allocator: Once::new(),
+        }
+    }
+    /// Places the window the first time it is used
+    fn allocator(&self) -> &Mutex<VirtualAddressAllocator> {
+        &self
+            .allocator
+            .call_once(|| {
+                let region = reserve_randomized(self.size, PageSize::Size1GiB.size())
+                    .expect("No room left for a kernel virtual window");
+                let allocator = VirtualAddressAllocator::new(region.start(), region.size());
+                (region, Mutex::new(allocator))
+            })
+            .1
+    }
+    /// Whether the window was placed and `address` is inside of it
+    pub fn contains(&self, address: usize) -> bool {
+        self.allocator
+            .get()
+            .is_some_and(|(region, _)| (region.start()..region.end()).contains(&address))
+    }
+    /// Reserves at least `size` bytes from the window
+    pub fn reserve(&self, size: usize) -> Option<VirtualRegion> {
+        self.allocator().lock().allocate(size.div_ceil(PAGE_SIZE))
+    }
+    /// Reserves at least `size` bytes from the window starting at a multiple of `align`
+    pub fn reserve_aligned(&self, size: usize, align: usize) -> Option<VirtualRegion> {
+        self.allocator()
+            .lock()
+            .allocate_aligned(size.div_ceil(PAGE_SIZE), align)
+    }
+}
+
+/// Where [`map_contiguous`] and so [`super::mmio::ioremap`] map device memory
+pub static MMIO_WINDOW: VirtualWindow = VirtualWindow::new(WINDOW_SIZE);
+/// Where [`KernelStack`]s are placed
+pub static STACK_WINDOW: VirtualWindow = VirtualWindow::new(WINDOW_SIZE);
+
+/// Reserves at least `size` bytes of kernel virtual addresses without mapping anything
+pub fn reserve(size: usize) -> Option<VirtualRegion> {
+    KERNEL_VIRTUAL_ALLOCATOR
+        .lock()
+        .allocate(size.div_ceil(PAGE_SIZE))
+}
+
+/// Reserves at least `size` bytes of kernel virtual addresses starting at a multiple of `align`
+pub fn reserve_aligned(size: usize, align: usize) -> Option<VirtualRegion> {
+    KERNEL_VIRTUAL_ALLOCATOR
+        .lock()
+        .allocate_aligned(size.div_ceil(PAGE_SIZE), align)
+}
+
+/// Reserves at least `size` bytes of kernel virtual addresses at a random multiple of `align` when [`kaslr`] is enabled,
+/// and at the same place on every boot otherwise
+pub fn reserve_randomized(size: usize, align: usize) -> Option<VirtualRegion> {
+    KERNEL_VIRTUAL_ALLOCATOR.lock().allocate_randomized(
+        size.div_ceil(PAGE_SIZE),
+        align,
+        kaslr::random(),
+    )
+}
+
+/// Gives back a region reserved with [`reserve`] or from a [`VirtualWindow`], it must not have anything mapped anymore
+pub fn release(region: VirtualRegion) {
+    for window in [&MMIO_WINDOW, &STACK_WINDOW] {
+        if window.contains(region.start()) {
+            window.allocator().lock().free(region);
+            return;
+        }
+    }
+    KERNEL_VIRTUAL_ALLOCATOR.lock().free(region)
+}
+
+/// Maps `frames` one after the other into a new contiguous virtual region
+///
+/// # Safety
+/// The frames must not be freed while they are mapped
+pub unsafe fn map_frames(
+    frames: impl ExactSizeIterator<Item = usize>,
+    flags: MemoryFlags,
+    mapper: &mut dyn MemoryMap,
+) -> Option<VirtualRegion> {
+    let region = reserve(frames.len() * PAGE_SIZE)?;
+    for (i, frame) in frames.enumerate() {
+        if !mapper.map_memory(region.start() + i * PAGE_SIZE, frame, flags) {
+            for page in 0..i {
+                mapper.unmap_memory(region.start() + page * PAGE_SIZE);
+            }
+            release(region);
+            return None;
+        }
+    }
+    Some(region)
+}
+
+/// Maps `size` bytes of physically contiguous memory starting at the page aligned `physical_address`
+///
+/// The region comes from [`MMIO_WINDOW`] and is placed so that huge pages can be used wherever the
+/// physical memory is aligned to them.
+/// Returns the region along with the virtual address `physical_address` was mapped to.
+///
+/// # Safety
+/// The memory must not be freed while it is mapped
+pub unsafe fn map_contiguous(
+    physical_address: usize,
+    size: usize,
+    flags: MemoryFlags,
+    mapper: &mut dyn MemoryMap,
+) -> Option<(VirtualRegion, usize)> {
+    let page_size = PageSize::ALL
+        .into_iter()
+        .find(|page_size| page_size.size() <= size)
+        .unwrap_or(PageSize::Size4KiB);
+    // The mapping starts at the same offset into a huge page as the physical memory
+    let offset = physical_address % page_size.size();
+    let region = MMIO_WINDOW.reserve_aligned(offset + size, page_size.size())?;
+    let address = region.start() + offset;
+    if !mapper.map_range(address..address + size, physical_address, flags, page_size) {
+        release(region);
+        return None;
+    }
+    Some((region, address))
+}
+
+/// Unmaps every page of `region` and releases it, the frames that were mapped are not freed
+///
+/// # Safety
+/// Nothing may access the region afterwards
+pub unsafe fn unmap_region(region: VirtualRegion, mapper: &mut dyn MemoryMap) {
+    mapper.unmap_range(region.start()..region.end());
+    release(region);
+}
+
+/// A kernel stack with an unmapped guard page below it, so overflowing it faults instead of corrupting memory
+pub struct KernelStack {
+    region: VirtualRegion,
+}
+impl KernelStack {
+    /// Allocates a stack of `pages` pages
+    pub fn new(pages: usize, mapper: &mut dyn MemoryMap) -> Option<Self> {
+        let region = STACK_WINDOW.reserve((pages + 1) * PAGE_SIZE)?;
+        let stack = Self { region };
+        for page in 0..pages {
+            let frame = GLOBAL_PAGE_ALLOCATOR.lock().request_page();
+            let mapped = frame.is_some_and(|frame| unsafe {
+                mapper.map_memory(
+                    stack.bottom() + page * PAGE_SIZE,
+                    frame.get(),
+                    MemoryFlags::WRITABLE | MemoryFlags::NO_EXECUTE,
+                )
+            });
+            if !mapped {
+                if let Some(frame) = frame {
+                    GLOBAL_PAGE_ALLOCATOR
+                        .lock()
+                        .free_pages(frame.get(), PAGE_SIZE);
+                }
+                unsafe { stack.destroy(mapper) };
+                return None;
+            }
+        }
+        Some(stack)
+    }
+    /// Lowest usable address of the stack, right above the guard page
+    pub fn bottom(&self) -> usize {
+        self.region.start() + PAGE_SIZE
+    }
+    /// Address the stack pointer starts at, stacks grow downwards
+    pub fn top(&self) -> usize {
+        self.region.end()
+    }
+    /// Unmaps the stack and frees its frames
+    ///
+    /// # Safety
+    /// The stack must not be in use
+    pub unsafe fn destroy(self, mapper: &mut dyn MemoryMap) {
+        for page in (self.bottom()..self.top()).step_by(PAGE_SIZE) {
+            if let Some(frame) = mapper.unmap_memory(page) {
+                GLOBAL_PAGE_ALLOCATOR.lock().free_pages(frame, PAGE_SIZE);
+            }
+        }
+        release(self.region);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ops::DerefMut;
+
+    use super::super::{memory_map::physical_to_virtual, KERNEL_MEMORY_MAP};
+    use super::*;
+
+    #[test(name = "Virtual address allocator hands out disjoint regions and merges freed ones")]
+    fn test_virtual_address_allocator() {
+        let start = KERNEL_VIRTUAL_START;
+        let mut allocator = VirtualAddressAllocator::new(start, 64 * PAGE_SIZE);
+        let first = allocator.allocate(16).unwrap();
+        let second = allocator.allocate(16).unwrap();
+        let third = allocator.allocate(16).unwrap();
+        assert_eq!(first.start(), start);
+        assert_eq!(second.start(), first.end());
+        assert_eq!(third.start(), second.end());
+        assert!(allocator.allocate(17).is_none());
+
+        allocator.free(first);
+        allocator.free(third);
+        assert_eq!(allocator.free_pages(), 48);
+        // The hole left by the first region is too small, the third was merged with the end
+        assert_eq!(
+            allocator.allocate(32).unwrap().start(),
+            start + 32 * PAGE_SIZE
+        );
+        allocator.free(second);
+        let whole = allocator.allocate(32).unwrap();
+        assert_eq!(whole.start(), start);
+        assert_eq!(allocator.free_pages(), 0);
+    }
+
+    #[test(name = "Virtual address allocator hands out aligned regions")]
+    fn test_aligned_virtual_regions() {
+        const ALIGN: usize = 512 * PAGE_SIZE;
+        let start = KERNEL_VIRTUAL_START + PAGE_SIZE;
+        let mut allocator = VirtualAddressAllocator::new(start, 4 * ALIGN);
+        let aligned = allocator.allocate_aligned(512, ALIGN).unwrap();
+        assert_eq!(aligned.start() % ALIGN, 0);
+        // The space skipped to align the region can still be used
+        let before = allocator.allocate(511).unwrap();
+        assert_eq!(before.start(), start);
+        let after = allocator.allocate_aligned(1, ALIGN).unwrap();
+        assert_eq!(after.start(), aligned.end());
+        allocator.free(aligned);
+        allocator.free(before);
+        allocator.free(after);
+        assert_eq!(allocator.free_pages(), 4 * 512);
+        assert_eq!(allocator.allocate(4 * 512).unwrap().start(), start);
+    }
+
+    #[test(name = "Randomized regions can start at every aligned place they fit")]
+    fn test_randomized_virtual_regions() {
+        let start = KERNEL_VIRTUAL_START;
+        let mut allocator = VirtualAddressAllocator::new(start, 64 * PAGE_SIZE);
+        // 16 pages fit at 49 places
+        let last = allocator.allocate_randomized(16, PAGE_SIZE, 48).unwrap();
+        assert_eq!(last.start(), start + 48 * PAGE_SIZE);
+        allocator.free(last);
+        let wrapped = allocator.allocate_randomized(16, PAGE_SIZE, 49).unwrap();
+        assert_eq!(wrapped.start(), start);
+        let middle = allocator.allocate_randomized(16, PAGE_SIZE, 10).unwrap();
+        assert_eq!(middle.start(), start + 26 * PAGE_SIZE);
+        // What is left is 10 pages before the middle region and 22 after it
+        let aligned = allocator.allocate_randomized(8, 8 * PAGE_SIZE, 1).unwrap();
+        assert_eq!(aligned.start(), start + 48 * PAGE_SIZE);
+        assert!(allocator.allocate_randomized(23, PAGE_SIZE, 0).is_none());
+        allocator.free(wrapped);
+        allocator.free(middle);
+        allocator.free(aligned);
+        assert_eq!(allocator.allocate(64).unwrap().start(), start);
+    }
+
+    #[test(name = "MMIO mappings and kernel stacks come from their own windows")]
+    fn test_virtual_windows() {
+        let mmio = MMIO_WINDOW.reserve(PAGE_SIZE).unwrap();
+        let stack = STACK_WINDOW.reserve(PAGE_SIZE).unwrap();
+        assert!(MMIO_WINDOW.contains(mmio.start()) && !STACK_WINDOW.contains(mmio.start()));
+        assert!(STACK_WINDOW.contains(stack.start()) && !MMIO_WINDOW.contains(stack.start()));
+        let general = reserve(PAGE_SIZE).unwrap();
+        assert!(!MMIO_WINDOW.contains(general.start()) && !STACK_WINDOW.contains(general.start()));
+        release(mmio);
+        release(stack);
+        release(general);
+    }
+
+    #[test(name = "Map non contiguous frames into one contiguous virtual region")]
+    fn test_map_frames() {
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        let mut allocator = GLOBAL_PAGE_ALLOCATOR.lock();
+        let frames = [
+            allocator.request_page().unwrap().get(),
+            allocator.request_page().unwrap().get(),
+            allocator.request_page().unwrap().get(),
+        ];
+        drop(allocator);
+        unsafe {
+            let region = map_frames(
+                frames.into_iter(),
+                MemoryFlags::WRITABLE,
+                mapper.deref_mut(),
+            )
+            .unwrap();
+            assert_eq!(region.size(), 3 * PAGE_SIZE);
+            for (i, &frame) in frames.iter().enumerate() {
+                ((region.start() + i * PAGE_SIZE) as *mut u64).write_volatile(i as u64 + 1);
+                assert_eq!(
+                    (physical_to_virtual(frame) as *const u64).read_volatile(),
+                    i as u64 + 1
+                );
+            }
+            unmap_region(region, mapper.deref_mut());
+        }
+        let mut allocator = GLOBAL_PAGE_ALLOCATOR.lock();
+        for frame in frames {
+            allocator.free_pages(frame, PAGE_SIZE);
+        }
+    }
+
+    #[test(name = "Allocate a kernel stack and use all of it")]
+    fn test_kernel_stack() {
+        let mut mapper = KERNEL_MEMORY_MAP.lock();
+        let stack = KernelStack::new(4, mapper.deref_mut()).unwrap();
+        assert_eq!(stack.top() - stack.bottom(), 4 * PAGE_SIZE);
+        unsafe {
+            (stack.bottom() as *mut u8).write_bytes(0x42, stack.top() - stack.bottom());
+            stack.destroy(mapper.deref_mut());
+        }
+    }
+}
@@ -5,3 +5,4 @@ pub static HHDM: HhdmRequest = HhdmRequest::new();
 pub static KERNEL_ADDRESS: KernelAddressRequest = KernelAddressRequest::new();
 pub static KERNEL_FILE: KernelFileRequest = KernelFileRequest::new();
 pub static SMP: SmpRequest = SmpRequest::new();
+pub static RSDP: RsdpRequest = RsdpRequest::new();
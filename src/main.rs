@@ -24,6 +24,7 @@ pub mod print;
 pub mod panic;
 pub mod arch;
 pub mod bitmap_allocator;
+pub mod buddy_allocator;
 pub mod limine;
 pub mod multicore;
 pub mod kernel;
@@ -34,12 +35,38 @@ use limine::BOOTLOADER_INFO;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    // The heap is demand paged, so page faults have to be handled before anything is allocated
+    #[cfg(target_arch = "x86_64")]
+    arch::x86_64::idt::init();
     print!("Booting NexOS v{}", env!("CARGO_PKG_VERSION"));
 
     if let Some(bootinfo) = BOOTLOADER_INFO.get_response() {
         print!(" with {} v{}", bootinfo.name(), bootinfo.version(),);
     }
     println!();
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        arch::x86_64::paging::init_pat();
+        arch::x86_64::user_access::enable_user_memory_protection();
+        arch::x86_64::kernel_image::remap_kernel();
+        arch::x86_64::gdt::init();
+        arch::x86_64::gdt::switch_to_kernel_stack(kernel_main)
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    kernel_main()
+}
+
+/// Rest of the boot, on a guarded kernel stack
+extern "C" fn kernel_main() -> ! {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        arch::x86_64::ioapic::init();
+        arch::x86_64::smp::start_application_processors();
+        arch::x86_64::timer::calibrate();
+        arch::x86_64::timer::start_periodic(kernel::clock::DEFAULT_TICK_FREQUENCY);
+    };
+    println!("{}", kernel::memory_stats::MemoryStats::snapshot());
+    kernel::init_core_caches();
     #[cfg(test)]
     test_main();
     panic!("Reached end of main function")
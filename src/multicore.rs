@@ -1,11 +1,17 @@
 
 pub mod local;
+pub mod lock;
 /// Gets the ID of whatever core calls this function
 pub fn current_core_id() -> usize {
     cfg_if::cfg_if! {
         if #[cfg(target_arch = "x86_64")] {
-            use crate::arch::x86_64::apic::LAPIC;
-            unsafe { LAPIC.read().id() }.try_into().expect("LAPIC ID must fit into a usize")
+            use crate::arch::x86_64::smp::cached_core_id;
+            if let Some(core) = cached_core_id() {
+                return core;
+            }
+            // The initial APIC ID is the LAPIC ID, and unlike the LAPIC it doesn't need the kernel memory map,
+            // whose lock asks for the ID of the core waiting for it
+            (unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24) as usize
         } else {
             todo!()
         }
@@ -33,5 +33,20 @@ impl<T> CoreLocal<T> {
     pub fn write(&self) -> RefMut<'_, T> {
         self.ensure_initialized()[current_core_id()].borrow_mut()
     }
+    /// Initializes the values for every core right away instead of on the first access
+    pub fn init(&self) {
+        self.ensure_initialized();
+    }
+    /// Borrows the value of the current core without initializing anything
+    ///
+    /// Returns None if the values are not initialized yet or the value is already borrowed,
+    /// which makes it usable from code the initialization itself depends on, like the allocator.
+    pub fn try_write(&self) -> Option<RefMut<'_, T>> {
+        self.inner
+            .get()?
+            .get(current_core_id())?
+            .try_borrow_mut()
+            .ok()
+    }
 }
 unsafe impl<T> Sync for CoreLocal<T> {}
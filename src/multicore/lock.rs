@@ -0,0 +1,91 @@
+use core::{
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+
+use super::current_core_id;
+
+/// Owner of a [`CoreMutex`] nobody holds
+const NO_CORE: usize = usize::MAX;
+
+/// Spin lock for memory that can be held while its holder waits for every other core
+///
+/// Waiting for it does the TLB shootdowns other cores send to this one, so a holder changing
+/// page tables still gets its shootdowns done by cores waiting with interrupts disabled.
+/// It also knows which core holds it, so code that can run while its core holds it, like the
+/// page fault handler, can tell whether waiting would ever end.
+pub struct CoreMutex<T> {
+    inner: Mutex<T>,
+    /// Core holding the lock, [`NO_CORE`] while it is free
+    owner: AtomicUsize,
+}
+impl<T> CoreMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            owner: AtomicUsize::new(NO_CORE),
+        }
+    }
+    pub fn lock(&self) -> CoreMutexGuard<'_, T> {
+        let core = current_core_id();
+        loop {
+            if let Some(guard) = self.try_lock_on(core) {
+                return guard;
+            }
+            while self.inner.is_locked() {
+                wait();
+            }
+        }
+    }
+    pub fn try_lock(&self) -> Option<CoreMutexGuard<'_, T>> {
+        self.try_lock_on(current_core_id())
+    }
+    fn try_lock_on(&self, core: usize) -> Option<CoreMutexGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        self.owner.store(core, Ordering::Relaxed);
+        Some(CoreMutexGuard {
+            guard,
+            owner: &self.owner,
+        })
+    }
+    /// Whether the current core holds the lock, waiting for it would never end then
+    pub fn is_held_by_current_core(&self) -> bool {
+        // Only this core stores its own ID, so it can't be seen here while another core holds the lock
+        self.owner.load(Ordering::Relaxed) == current_core_id()
+    }
+}
+
+/// Does what other cores wait for while this one waits for a lock
+fn wait() {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "x86_64")] {
+            crate::arch::x86_64::tlb::process_shootdowns();
+        }
+    }
+    spin_loop();
+}
+
+pub struct CoreMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+impl<T> Drop for CoreMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Cleared before the lock is released by dropping `guard`
+        self.owner.store(NO_CORE, Ordering::Relaxed);
+    }
+}
+impl<T> Deref for CoreMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for CoreMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}